@@ -3,6 +3,8 @@
 // found in the LICENSE file.
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
 use std::os::fd::AsRawFd;
 use std::rc::Rc;
 
@@ -14,6 +16,35 @@ use crate::device::v4l2::stateless::device::V4l2Device;
 use crate::device::v4l2::stateless::queue::V4l2CaptureBuffer;
 use crate::device::v4l2::stateless::queue::V4l2OutputBuffer;
 
+/// Error produced by a [`V4l2Request`]. Once a request fails with `Self::Ioctl` it stays in a
+/// terminal error state: later `timestamp()` and `result()` calls return that same error, and
+/// `ioctl()`, `write()`, `submit()` and `sync()` return `Self::InvalidState`, instead of panicking.
+#[derive(Debug, Clone)]
+pub enum RequestError {
+    /// A `VIDIOC_S_EXT_CTRLS` or request-queue ioctl on the device failed.
+    Ioctl(String),
+    /// `method` was called on a request that wasn't in the state it requires, e.g. `submit()` on
+    /// a request that was already submitted. This indicates a bug in the caller rather than a
+    /// device failure, so the request is left in whatever state it was already in.
+    InvalidState {
+        method: &'static str,
+        state: &'static str,
+    },
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ioctl(msg) => write!(f, "v4l2 request ioctl failed: {msg}"),
+            Self::InvalidState { method, state } => {
+                write!(f, "called `{method}()` on a request in the `{state}` state")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
 struct InitRequestHandle {
     device: V4l2Device,
     timestamp: u64,
@@ -35,27 +66,28 @@ impl InitRequestHandle {
             buffer,
         }
     }
-    fn ioctl<C, T>(&mut self, ctrl: C) -> &mut Self
+    fn ioctl<C, T>(&mut self, ctrl: C) -> Result<(), RequestError>
     where
         C: Into<SafeExtControl<T>>,
         T: ExtControlTrait,
     {
         let which = ioctl::CtrlWhich::Request(self.handle.as_raw_fd());
         let mut ctrl: SafeExtControl<T> = ctrl.into();
-        ioctl::s_ext_ctrls(&self.device, which, &mut ctrl).expect("Failed to set output control");
-        self
+        ioctl::s_ext_ctrls(&self.device, which, &mut ctrl)
+            .map_err(|e| RequestError::Ioctl(e.to_string()))
     }
-    fn write(&mut self, data: &[u8]) -> &mut Self {
+    fn write(&mut self, data: &[u8]) {
         self.buffer.write(data);
-        self
     }
-    fn submit(self) -> PendingRequestHandle {
+    fn submit(self) -> Result<PendingRequestHandle, RequestError> {
         self.buffer.submit(self.timestamp, self.handle.as_raw_fd());
-        self.handle.queue().expect("Failed to queue request handle");
-        PendingRequestHandle {
+        self.handle
+            .queue()
+            .map_err(|e| RequestError::Ioctl(e.to_string()))?;
+        Ok(PendingRequestHandle {
             device: self.device.clone(),
             timestamp: self.timestamp,
-        }
+        })
     }
 }
 
@@ -89,6 +121,7 @@ enum RequestHandle {
     Init(InitRequestHandle),
     Pending(PendingRequestHandle),
     Done(DoneRequestHandle),
+    Error(RequestError),
     #[default]
     Unknown,
 }
@@ -102,52 +135,90 @@ impl RequestHandle {
     ) -> Self {
         Self::Init(InitRequestHandle::new(device, timestamp, handle, buffer))
     }
-    fn timestamp(&self) -> u64 {
+    fn state_name(&self) -> &'static str {
+        match self {
+            Self::Init(_) => "Init",
+            Self::Pending(_) => "Pending",
+            Self::Done(_) => "Done",
+            Self::Error(_) => "Error",
+            Self::Unknown => "Unknown",
+        }
+    }
+    fn invalid_state(&self, method: &'static str) -> RequestError {
+        RequestError::InvalidState {
+            method,
+            state: self.state_name(),
+        }
+    }
+    fn timestamp(&self) -> Result<u64, RequestError> {
         match self {
-            Self::Init(handle) => handle.timestamp,
-            Self::Pending(handle) => handle.timestamp,
-            Self::Done(handle) => handle.buffer.borrow().timestamp(),
-            _ => panic!("ERROR"),
+            Self::Init(handle) => Ok(handle.timestamp),
+            Self::Pending(handle) => Ok(handle.timestamp),
+            Self::Done(handle) => Ok(handle.buffer.borrow().timestamp()),
+            Self::Error(e) => Err(e.clone()),
+            Self::Unknown => Err(self.invalid_state("timestamp")),
         }
     }
-    fn ioctl<C, T>(&mut self, ctrl: C) -> &mut Self
+    fn ioctl<C, T>(&mut self, ctrl: C) -> Result<(), RequestError>
     where
         C: Into<SafeExtControl<T>>,
         T: ExtControlTrait,
     {
-        match self {
-            Self::Init(handle) => handle.ioctl(ctrl),
-            _ => panic!("ERROR"),
+        let Self::Init(handle) = self else {
+            return Err(self.invalid_state("ioctl"));
         };
-        self
+        if let Err(e) = handle.ioctl(ctrl) {
+            *self = Self::Error(e.clone());
+            return Err(e);
+        }
+        Ok(())
     }
-    fn write(&mut self, data: &[u8]) -> &mut Self {
-        match self {
-            Self::Init(handle) => handle.write(data),
-            _ => panic!("ERROR"),
+    fn write(&mut self, data: &[u8]) -> Result<(), RequestError> {
+        let Self::Init(handle) = self else {
+            return Err(self.invalid_state("write"));
         };
-        self
+        handle.write(data);
+        Ok(())
     }
 
     // This method can modify in-place instead of returning a new value. This removes the need for
     // a RefCell in V4l2Request.
-    fn submit(&mut self) {
-        match std::mem::take(self) {
-            Self::Init(handle) => *self = Self::Pending(handle.submit()),
-            _ => panic!("ERROR"),
+    fn submit(&mut self) -> Result<(), RequestError> {
+        if !matches!(self, Self::Init(_)) {
+            return Err(self.invalid_state("submit"));
+        }
+        let Self::Init(handle) = std::mem::take(self) else {
+            unreachable!("checked above")
+        };
+        match handle.submit() {
+            Ok(pending) => {
+                *self = Self::Pending(pending);
+                Ok(())
+            }
+            Err(e) => {
+                *self = Self::Error(e.clone());
+                Err(e)
+            }
         }
     }
-    fn sync(&mut self) {
-        match std::mem::take(self) {
-            Self::Pending(handle) => *self = Self::Done(handle.sync()),
-            s @ Self::Done(_) => *self = s,
-            _ => panic!("ERROR"),
+    fn sync(&mut self) -> Result<(), RequestError> {
+        if matches!(self, Self::Done(_)) {
+            return Ok(());
+        }
+        if !matches!(self, Self::Pending(_)) {
+            return Err(self.invalid_state("sync"));
         }
+        let Self::Pending(handle) = std::mem::take(self) else {
+            unreachable!("checked above")
+        };
+        *self = Self::Done(handle.sync());
+        Ok(())
     }
-    fn result(&self) -> V4l2Result {
+    fn result(&self) -> Result<V4l2Result, RequestError> {
         match self {
-            Self::Done(handle) => handle.result(),
-            _ => panic!("ERROR"),
+            Self::Done(handle) => Ok(handle.result()),
+            Self::Error(e) => Err(e.clone()),
+            _ => Err(self.invalid_state("result")),
         }
     }
 }
@@ -163,30 +234,37 @@ impl V4l2Request {
     ) -> Self {
         Self(RequestHandle::new(device, timestamp, handle, buffer))
     }
-    pub fn timestamp(&self) -> u64 {
+    pub fn timestamp(&self) -> Result<u64, RequestError> {
         self.0.timestamp()
     }
-    pub fn ioctl<C, T>(&mut self, ctrl: C) -> &mut Self
+    pub fn ioctl<C, T>(&mut self, ctrl: C) -> Result<&mut Self, RequestError>
     where
         C: Into<SafeExtControl<T>>,
         T: ExtControlTrait,
     {
-        self.0.ioctl(ctrl);
-        self
+        self.0.ioctl(ctrl)?;
+        Ok(self)
     }
-    pub fn write(&mut self, data: &[u8]) -> &mut Self {
-        self.0.write(data);
-        self
+    pub fn write(&mut self, data: &[u8]) -> Result<&mut Self, RequestError> {
+        self.0.write(data)?;
+        Ok(self)
     }
-    pub fn submit(&mut self) {
-        self.0.submit();
+    pub fn submit(&mut self) -> Result<(), RequestError> {
+        self.0.submit()
     }
-    pub fn sync(&mut self) {
-        self.0.sync();
+    pub fn sync(&mut self) -> Result<(), RequestError> {
+        self.0.sync()
     }
-    pub fn result(&self) -> V4l2Result {
+    pub fn result(&self) -> Result<V4l2Result, RequestError> {
         self.0.result()
     }
+    /// Returns the error that moved this request into its terminal `Error` state, if any.
+    pub fn error(&self) -> Option<&RequestError> {
+        match &self.0 {
+            RequestHandle::Error(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 pub struct V4l2Result {
@@ -201,3 +279,69 @@ impl V4l2Result {
         self.buffer.borrow().read(data)
     }
 }
+
+/// Pipelines submission of several in-flight [`V4l2Request`]s instead of submitting and
+/// synchronizing one at a time.
+///
+/// A stateless decoder queues one request per frame, and the device's OUTPUT (bitstream) and
+/// CAPTURE (decoded picture) queues are processed independently of each other; syncing a request
+/// before submitting the next leaves the device idle between frames. Keeping up to `depth`
+/// requests in flight lets it start decoding request N+1 while request N is still in progress.
+pub struct V4l2RequestPipeline {
+    depth: usize,
+    pending: VecDeque<V4l2Request>,
+}
+
+impl V4l2RequestPipeline {
+    /// `depth` is the number of requests left in flight when `submit` returns; it must be at
+    /// least 1. `submit` queues the new request before syncing the oldest one, so up to
+    /// `depth + 1` requests are briefly in flight at once.
+    pub fn new(depth: usize) -> Self {
+        assert!(depth >= 1, "a pipeline depth of 0 would never keep a request in flight");
+        Self {
+            depth,
+            pending: VecDeque::with_capacity(depth),
+        }
+    }
+
+    /// Submits `request` and pipelines it behind whatever is already in flight. If the pipeline
+    /// was already at `depth` requests, this also syncs the oldest of them and returns its
+    /// result; otherwise it returns `None`, meaning the request was queued but nothing has been
+    /// waited on yet.
+    pub fn submit(
+        &mut self,
+        mut request: V4l2Request,
+    ) -> Result<Option<V4l2Result>, RequestError> {
+        request.submit()?;
+        self.pending.push_back(request);
+
+        if self.pending.len() <= self.depth {
+            return Ok(None);
+        }
+
+        // unwrap: a request was just pushed, so `pending` can't be empty here.
+        let mut oldest = self.pending.pop_front().unwrap();
+        oldest.sync()?;
+        Ok(Some(oldest.result()?))
+    }
+
+    /// Syncs and returns the results of every request still in flight, oldest first. Call this at
+    /// end-of-stream or on a flush to collect results `submit` hasn't returned yet.
+    pub fn drain(&mut self) -> Result<Vec<V4l2Result>, RequestError> {
+        let mut results = Vec::with_capacity(self.pending.len());
+        while let Some(mut request) = self.pending.pop_front() {
+            request.sync()?;
+            results.push(request.result()?);
+        }
+        Ok(results)
+    }
+
+    /// Number of requests currently in flight.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
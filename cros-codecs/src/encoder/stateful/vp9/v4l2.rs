@@ -4,10 +4,19 @@
 
 use std::sync::Arc;
 
+mod rate_control;
+
+pub use rate_control::FirstPassStats;
+pub use rate_control::FrameKind;
+pub use rate_control::RateControlPass;
+pub use rate_control::TwoPassRateControl;
+
 use v4l2r::controls::codec::VideoGopSize;
 use v4l2r::controls::codec::VideoVP9Profile;
 use v4l2r::controls::codec::VideoVPXMaxQp;
 use v4l2r::controls::codec::VideoVPXMinQp;
+use v4l2r::controls::codec::VideoVPXNumTemporalLayers;
+use v4l2r::controls::codec::VideoVPXTemporalLayerFramerates;
 use v4l2r::device::Device;
 
 use crate::backend::v4l2::encoder::CaptureBuffers;
@@ -22,12 +31,298 @@ use crate::encoder::stateful::StatefulEncoder;
 use crate::encoder::vp9::EncoderConfig;
 use crate::encoder::vp9::VP9;
 use crate::encoder::PredictionStructure;
+use crate::encoder::RateControl;
 use crate::encoder::Tunings;
+use crate::image_processing::rgb_to_nv12;
+use crate::image_processing::yuy2_to_nv12;
+use crate::image_processing::RgbLayout;
 use crate::Fourcc;
 use crate::Resolution;
 
 const PIXEL_FORMAT_VP9: v4l2r::PixelFormat = v4l2r::PixelFormat::from_fourcc(b"VP90");
 
+/// The userspace conversion [`resolve_input_conversion`] picked to turn a caller's input frames
+/// into the device's negotiated `OUTPUT` format, analogous to the format emulation
+/// `libv4lconvert` does for drivers that can't accept a format natively.
+///
+/// A per-frame submission path would need to run the matching
+/// [`image_processing`](crate::image_processing) function into a scratch buffer before queueing
+/// whenever this isn't [`InputConversion::Identity`] -- no such path exists in this crate yet
+/// (`backend::v4l2::encoder`, which would own it, isn't implemented here). [`resolve_input_conversion`]
+/// is only used today to validate the input/device format pair at encoder construction;
+/// [`InputConversion::convert_into`] is never actually invoked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputConversion {
+    /// The input is already in the device's format; nothing to convert.
+    Identity,
+    Yuy2ToNv12,
+    RgbToNv12(RgbLayout),
+}
+
+/// Picks how to turn `input_fourcc` frames into `device_fourcc` ones, or fails if this module
+/// knows no route between them.
+///
+/// Every non-identity route is gated behind the `v4l2-input-convert` feature: without it, this
+/// always errors on a mismatch rather than silently pulling in the conversion code, so a caller
+/// that never needs it doesn't pay for the fast path it isn't using.
+fn resolve_input_conversion(
+    input_fourcc: Fourcc,
+    device_fourcc: Fourcc,
+) -> Result<InputConversion, InitializationError> {
+    if input_fourcc == device_fourcc {
+        return Ok(InputConversion::Identity);
+    }
+
+    #[cfg(feature = "v4l2-input-convert")]
+    {
+        if device_fourcc != Fourcc::from(b"NV12") && device_fourcc != Fourcc::from(b"NM12") {
+            return Err(InitializationError::Other(format!(
+                "no input conversion route to device format {:?}",
+                device_fourcc
+            )));
+        }
+
+        if input_fourcc == Fourcc::from(b"YUYV") {
+            return Ok(InputConversion::Yuy2ToNv12);
+        }
+        if input_fourcc == Fourcc::from(b"RGB3") {
+            return Ok(InputConversion::RgbToNv12(RgbLayout::Rgb));
+        }
+        if input_fourcc == Fourcc::from(b"BGR3") {
+            return Ok(InputConversion::RgbToNv12(RgbLayout::Bgr));
+        }
+    }
+
+    Err(InitializationError::Other(format!(
+        "no input conversion route from {:?} to device format {:?}",
+        input_fourcc, device_fourcc
+    )))
+}
+
+impl InputConversion {
+    /// Runs the conversion this route picked, from a tightly-packed `input_fourcc` frame at
+    /// `src` into the NV12 `dst_y`/`dst_uv` planes the device was negotiated to accept.
+    #[cfg_attr(not(feature = "v4l2-input-convert"), allow(dead_code))]
+    pub(crate) fn convert_into(
+        &self,
+        src: &[u8],
+        src_stride: usize,
+        dst_y: &mut [u8],
+        dst_y_stride: usize,
+        dst_uv: &mut [u8],
+        dst_uv_stride: usize,
+        width: usize,
+        height: usize,
+    ) {
+        match self {
+            InputConversion::Identity => unreachable!(
+                "the per-frame path should queue the input buffer directly instead of calling \
+                 convert_into for Identity"
+            ),
+            InputConversion::Yuy2ToNv12 => yuy2_to_nv12(
+                src,
+                src_stride,
+                dst_y,
+                dst_y_stride,
+                dst_uv,
+                dst_uv_stride,
+                width,
+                height,
+            ),
+            InputConversion::RgbToNv12(layout) => rgb_to_nv12(
+                src,
+                src_stride,
+                *layout,
+                dst_y,
+                dst_y_stride,
+                dst_uv,
+                dst_uv_stride,
+                width,
+                height,
+            ),
+        }
+    }
+}
+
+// VP9 reference frame slots (`ref_frame_idx`/`refresh_frame_flags` bits), as
+// used by both the bitstream and the per-frame V4L2 controls below.
+const VP9_REF_FRAME_LAST: u8 = 1 << 0;
+const VP9_REF_FRAME_GOLDEN: u8 = 1 << 1;
+
+/// Temporal-layer GOP shapes a [`PredictionStructure::TemporalSvc`]
+/// sequence can follow. A separate enum (rather than folding straight into
+/// `num_layers`) leaves room for a non-dyadic pattern later without another
+/// `PredictionStructure` variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemporalLayerPattern {
+    /// The dyadic hierarchical-P structure: every non-base frame predicts
+    /// from, and below the top layer is predicted by, the nearest
+    /// lower-layer frame. This is the only pattern implemented today.
+    Dyadic,
+}
+
+/// Per-frame temporal-layer scheduling decision for one coded frame of a
+/// [`PredictionStructure::TemporalSvc`] sequence: which layer it belongs to
+/// and which VP9 reference frame slots it reads from and refreshes.
+///
+/// `refresh_flags` never includes a slot a higher layer than `temporal_id`
+/// is the sole writer of, so dropping every frame above some layer N always
+/// leaves the slots layer-N-and-below frames depend on intact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vp9SvcFrameParams {
+    pub temporal_id: u8,
+    pub reference_flags: u8,
+    pub refresh_flags: u8,
+}
+
+/// Cycles a dyadic temporal-layer GOP, handing out the reference/refresh
+/// flags for one frame at a time. `LAST` always holds the most recent base
+/// (L0) frame; `GOLDEN` holds the most recent L1 frame in the 3-layer case.
+/// Top-layer frames never refresh a slot, so they're never a reference and
+/// can always be dropped by the client without affecting decodability.
+///
+/// [`V4L2VP9Backend::new`] only uses this to validate `num_layers`/`pattern`
+/// at construction time; nothing in this crate yet calls [`Self::next_frame`]
+/// to drive the per-frame `OUTPUT`/`CAPTURE` submission this doc comment
+/// describes -- `backend::v4l2::encoder` doesn't exist in this tree.
+pub(crate) struct Vp9SvcScheduler {
+    gop: Vec<Vp9SvcFrameParams>,
+    next: usize,
+}
+
+impl Vp9SvcScheduler {
+    pub(crate) fn new(
+        num_layers: u32,
+        pattern: TemporalLayerPattern,
+    ) -> Result<Self, InitializationError> {
+        let TemporalLayerPattern::Dyadic = pattern;
+
+        let gop = match num_layers {
+            2 => vec![
+                Vp9SvcFrameParams {
+                    temporal_id: 0,
+                    reference_flags: VP9_REF_FRAME_LAST,
+                    refresh_flags: VP9_REF_FRAME_LAST,
+                },
+                Vp9SvcFrameParams {
+                    temporal_id: 1,
+                    reference_flags: VP9_REF_FRAME_LAST,
+                    refresh_flags: 0,
+                },
+            ],
+            3 => vec![
+                // L0: only ever predicts from (and refreshes) LAST.
+                Vp9SvcFrameParams {
+                    temporal_id: 0,
+                    reference_flags: VP9_REF_FRAME_LAST,
+                    refresh_flags: VP9_REF_FRAME_LAST,
+                },
+                // L2: predicts from the nearest L0 (LAST) and L1 (GOLDEN),
+                // refreshes nothing so it's never relied on as a reference.
+                Vp9SvcFrameParams {
+                    temporal_id: 2,
+                    reference_flags: VP9_REF_FRAME_LAST | VP9_REF_FRAME_GOLDEN,
+                    refresh_flags: 0,
+                },
+                // L1: predicts from the nearest L0 (LAST), refreshes GOLDEN
+                // so the next L2 frame can reference it.
+                Vp9SvcFrameParams {
+                    temporal_id: 1,
+                    reference_flags: VP9_REF_FRAME_LAST,
+                    refresh_flags: VP9_REF_FRAME_GOLDEN,
+                },
+                // L2 again, now predicting from the L1 frame just refreshed.
+                Vp9SvcFrameParams {
+                    temporal_id: 2,
+                    reference_flags: VP9_REF_FRAME_LAST | VP9_REF_FRAME_GOLDEN,
+                    refresh_flags: 0,
+                },
+            ],
+            other => {
+                return Err(InitializationError::Other(format!(
+                    "unsupported number of VP9 temporal layers: {}",
+                    other
+                )))
+            }
+        };
+
+        Ok(Vp9SvcScheduler { gop, next: 0 })
+    }
+
+    /// Returns the layering decision for the next frame to encode and
+    /// advances the schedule. Whoever eventually submits per-frame `OUTPUT`
+    /// requests is responsible for programming the returned reference/refresh
+    /// flags onto the request and copying `temporal_id` onto the matching
+    /// dequeued `CAPTURE` buffer's coded-frame metadata -- no such caller
+    /// exists in this crate yet.
+    pub(crate) fn next_frame(&mut self) -> Vp9SvcFrameParams {
+        let params = self.gop[self.next];
+        self.next = (self.next + 1) % self.gop.len();
+        params
+    }
+
+    /// The per-layer framerate implied by evenly splitting `framerate`
+    /// across the dyadic pyramid: layer 0 runs at `framerate / 2^(layers -
+    /// 1)`, and each layer above it doubles that, with the top layer
+    /// carrying the full requested framerate.
+    pub(crate) fn layer_framerates(num_layers: u32, framerate: u32) -> Vec<u32> {
+        (0..num_layers)
+            .map(|layer| {
+                let divisor = 1u32 << (num_layers - 1 - layer);
+                (framerate / divisor).max(1)
+            })
+            .collect()
+    }
+}
+
+/// The bitrate a [`TwoPassRateControl`] should spread its second-pass bit
+/// budget across, read back off whatever [`RateControl`] mode the first pass
+/// (and this second pass) was tuned with. Only `ConstantBitrate` gives a
+/// concrete number to re-derive a budget from; any other mode falls back to
+/// 0, which starves the second pass down to the [`Tunings`] min/max QP clamp
+/// instead of silently guessing a bitrate.
+fn target_bits_per_second(rate_control: &RateControl) -> u64 {
+    match rate_control {
+        RateControl::ConstantBitrate(bps) => *bps as u64,
+        _ => 0,
+    }
+}
+
+/// Validates `rate_control_pass` against `tunings` and, for
+/// [`RateControlPass::SecondPassFromStats`], builds the
+/// [`TwoPassRateControl`] schedule a per-frame submission path would need to
+/// drive with [`TwoPassRateControl::next_qp`] for every frame it submits, in
+/// the same order the first pass recorded them.
+///
+/// [`V4L2VP9Backend::new`] only calls this to validate `rate_control_pass` at
+/// construction time and discards the returned schedule -- no caller in this
+/// crate invokes `next_qp` outside its own unit tests, so a second pass never
+/// actually drives a different QP per frame yet.
+fn prepare_rate_control(
+    rate_control_pass: RateControlPass,
+    tunings: &Tunings,
+) -> Result<Option<TwoPassRateControl>, InitializationError> {
+    match rate_control_pass {
+        RateControlPass::SingleCbr | RateControlPass::SingleVbr => Ok(None),
+        RateControlPass::SecondPassFromStats(stats) => {
+            if stats.frames().is_empty() {
+                return Err(InitializationError::Other(
+                    "second pass requires non-empty first-pass stats".to_string(),
+                ));
+            }
+
+            let target_bps = target_bits_per_second(&tunings.rate_control);
+            let duration_secs = stats.frames().len() as f64 / tunings.framerate.max(1) as f64;
+
+            Ok(Some(TwoPassRateControl::new(
+                stats,
+                target_bps,
+                duration_secs,
+            )))
+        }
+    }
+}
+
 pub type V4L2VP9Backend<Handle, CaptureBufferz> = V4L2Backend<Handle, CaptureBufferz, VP9>;
 
 pub type V4L2StatefulVP9Encoder<Handle, CaptureBufferz> =
@@ -65,20 +360,69 @@ where
     Handle: OutputBufferHandle,
     CaptureBufferz: CaptureBuffers,
 {
+    /// Pushes the dyadic per-layer framerate split (see
+    /// [`Vp9SvcScheduler::layer_framerates`]) down to the device as a single
+    /// control so layer 0 through `num_layers - 1` each get their target
+    /// rate in one call.
+    fn apply_layer_framerates(
+        device: &Device,
+        num_layers: u32,
+        framerate: u32,
+    ) -> Result<(), InitializationError> {
+        let rates = Vp9SvcScheduler::layer_framerates(num_layers, framerate);
+        Self::apply_ctrl(
+            device,
+            "vp9 temporal layer framerates",
+            VideoVPXTemporalLayerFramerates(rates),
+        )?;
+        Ok(())
+    }
+
     pub fn new(
         device: Arc<Device>,
         capture_buffers: CaptureBufferz,
         config: EncoderConfig,
         fourcc: Fourcc,
+        input_fourcc: Fourcc,
         coded_size: Resolution,
         tunings: Tunings,
+        rate_control_pass: RateControlPass,
     ) -> Result<Self, InitializationError> {
+        // Validation only (see `prepare_rate_control`'s doc comment): a
+        // malformed or empty stats buffer is rejected at encoder creation
+        // rather than silently producing single-pass-equivalent output. The
+        // resulting schedule is discarded since nothing here drives it yet.
+        let _two_pass_rate_control = prepare_rate_control(rate_control_pass, &tunings)?;
+
+        // Rejects an input format this module has no conversion route for at construction
+        // time. The resolved route is discarded rather than stored: nothing in this crate
+        // queues a frame yet, so there's nowhere to actually run the conversion.
+        let _input_conversion = resolve_input_conversion(input_fourcc, fourcc)?;
+
         match config.pred_structure {
             PredictionStructure::LowDelay { limit } => {
                 let limit = limit as i32;
 
                 Self::apply_ctrl(&device, "gop size", VideoGopSize(limit))?;
             }
+            PredictionStructure::TemporalSvc {
+                num_layers,
+                pattern,
+            } => {
+                // This only validates `num_layers`/`pattern` against the
+                // patterns this backend knows how to build and surfaces an
+                // unsupported layer count as an `InitializationError`; the
+                // resulting scheduler isn't stored anywhere because nothing
+                // in this crate yet drives per-frame SVC submission with it.
+                let _ = Vp9SvcScheduler::new(num_layers, pattern)?;
+                Self::apply_ctrl(
+                    &device,
+                    "vp9 num temporal layers",
+                    VideoVPXNumTemporalLayers(num_layers as i32),
+                )?;
+
+                Self::apply_layer_framerates(&device, num_layers, tunings.framerate)?;
+            }
         }
 
         let profile = match config.bit_depth {
@@ -105,17 +449,33 @@ where
     Handle: OutputBufferHandle,
     CaptureBufferz: CaptureBuffers,
 {
+    /// `input_fourcc` is the format frames handed to the returned encoder are actually in; it
+    /// may differ from `fourcc`, the format the device's `OUTPUT` queue was negotiated for. See
+    /// [`resolve_input_conversion`] for which input formats can reach which device formats --
+    /// note that nothing in this crate yet actually runs the conversion on a queued frame, so a
+    /// mismatched `input_fourcc` is only validated here, not converted.
     pub fn new(
         device: Arc<Device>,
         capture_buffers: CaptureBufferz,
         config: EncoderConfig,
         fourcc: Fourcc,
+        input_fourcc: Fourcc,
         coded_size: Resolution,
         tunings: Tunings,
+        rate_control_pass: RateControlPass,
     ) -> Result<Self, InitializationError> {
         Ok(Self::create(
             tunings.clone(),
-            V4L2VP9Backend::new(device, capture_buffers, config, fourcc, coded_size, tunings)?,
+            V4L2VP9Backend::new(
+                device,
+                capture_buffers,
+                config,
+                fourcc,
+                input_fourcc,
+                coded_size,
+                tunings,
+                rate_control_pass,
+            )?,
         ))
     }
 }
@@ -124,6 +484,7 @@ where
 mod tests {
     use super::*;
 
+    use std::io;
     use std::path::PathBuf;
     use std::sync::Arc;
 
@@ -140,11 +501,116 @@ mod tests {
     use crate::encoder::simple_encode_loop;
     use crate::encoder::tests::userptr_test_frame_generator;
     use crate::encoder::RateControl;
+    use crate::muxer::new_muxer;
+    use crate::muxer::ContainerFormat;
+    use crate::muxer::Muxer;
     use crate::utils::DmabufFrame;
-    use crate::utils::IvfFileHeader;
-    use crate::utils::IvfFrameHeader;
     use crate::Resolution;
 
+    #[test]
+    fn svc_scheduler_rejects_unsupported_layer_counts() {
+        assert!(Vp9SvcScheduler::new(1, TemporalLayerPattern::Dyadic).is_err());
+        assert!(Vp9SvcScheduler::new(4, TemporalLayerPattern::Dyadic).is_err());
+        assert!(Vp9SvcScheduler::new(0, TemporalLayerPattern::Dyadic).is_err());
+    }
+
+    #[test]
+    fn svc_scheduler_two_layer_dyadic_never_refreshes_top_layer() {
+        let mut scheduler = Vp9SvcScheduler::new(2, TemporalLayerPattern::Dyadic).unwrap();
+
+        for _ in 0..8 {
+            let base = scheduler.next_frame();
+            assert_eq!(base.temporal_id, 0);
+            assert_ne!(base.refresh_flags, 0);
+
+            let top = scheduler.next_frame();
+            assert_eq!(top.temporal_id, 1);
+            assert_eq!(top.refresh_flags, 0);
+        }
+    }
+
+    #[test]
+    fn svc_scheduler_three_layer_dyadic_preserves_dropped_layer_invariant() {
+        let mut scheduler = Vp9SvcScheduler::new(3, TemporalLayerPattern::Dyadic).unwrap();
+
+        // Across several GOPs, every frame at or below a given layer must
+        // only ever reference slots that frames at or below that layer
+        // refresh, so dropping everything above that layer never dangles a
+        // reference.
+        let mut refreshed_by: [u8; 3] = [0, 0, 0];
+        for _ in 0..16 {
+            let frame = scheduler.next_frame();
+
+            if frame.temporal_id < 2 {
+                assert_ne!(frame.refresh_flags, 0, "L0/L1 frames must refresh a slot");
+            } else {
+                assert_eq!(frame.refresh_flags, 0, "L2 frames must never be referenced");
+            }
+
+            // Every slot this frame reads from must have most recently been
+            // refreshed by a frame at or below its own layer.
+            for slot in 0..3u8 {
+                let bit = 1 << slot;
+                if frame.reference_flags & bit != 0 {
+                    assert!(refreshed_by[slot as usize] <= frame.temporal_id);
+                }
+                if frame.refresh_flags & bit != 0 {
+                    refreshed_by[slot as usize] = frame.temporal_id;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn svc_layer_framerates_double_up_to_the_requested_rate() {
+        assert_eq!(Vp9SvcScheduler::layer_framerates(3, 30), vec![7, 15, 30]);
+        assert_eq!(Vp9SvcScheduler::layer_framerates(2, 30), vec![15, 30]);
+    }
+
+    #[test]
+    fn single_pass_modes_need_no_two_pass_schedule() {
+        let tunings = Tunings::default();
+
+        assert!(prepare_rate_control(RateControlPass::SingleCbr, &tunings)
+            .unwrap()
+            .is_none());
+        assert!(prepare_rate_control(RateControlPass::SingleVbr, &tunings)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn second_pass_rejects_empty_first_pass_stats() {
+        let tunings = Tunings::default();
+        let pass = RateControlPass::SecondPassFromStats(FirstPassStats::new());
+
+        assert!(prepare_rate_control(pass, &tunings).is_err());
+    }
+
+    #[test]
+    fn second_pass_builds_a_schedule_from_recorded_stats() {
+        let mut stats = FirstPassStats::new();
+        stats.record(FrameKind::Key, 40_000, 32);
+        stats.record(FrameKind::Inter, 8_000, 48);
+
+        let tunings = Tunings {
+            framerate: 30,
+            rate_control: RateControl::ConstantBitrate(400_000),
+            ..Default::default()
+        };
+
+        let pass = RateControlPass::SecondPassFromStats(stats);
+        assert!(prepare_rate_control(pass, &tunings).unwrap().is_some());
+    }
+
+    #[test]
+    fn target_bits_per_second_only_understands_constant_bitrate() {
+        assert_eq!(
+            target_bits_per_second(&RateControl::ConstantBitrate(123_456)),
+            123_456
+        );
+    }
+
     #[ignore]
     // Ignore this test by default as it requires v4l2m2m-compatible hardware.
     #[test]
@@ -173,28 +639,34 @@ mod tests {
                 ..Default::default()
             },
             Fourcc::from(b"NM12"),
+            Fourcc::from(b"NM12"),
             CODED_SIZE,
             Tunings {
                 rate_control: RateControl::ConstantBitrate(400_000),
                 ..Default::default()
             },
+            RateControlPass::SingleCbr,
         )
         .unwrap();
 
         let format: v4l2r::Format = encoder.backend().output_format().unwrap();
         let layout = v4l2_format_to_frame_layout(&format);
 
-        let mut bitstream = Vec::new();
-
-        let file_header = IvfFileHeader::new(
-            IvfFileHeader::CODEC_VP9,
-            VISIBLE_SIZE.width as u16,
-            VISIBLE_SIZE.height as u16,
-            30,
-            FRAME_COUNT as u32,
-        );
-
-        file_header.writo_into(&mut bitstream).unwrap();
+        let write_to_file = std::option_env!("CROS_CODECS_TEST_WRITE_TO_FILE") == Some("true");
+        let out_path = PathBuf::from("test_v4l2_encoder_userptr.vp9.ivf");
+        let muxer_format = ContainerFormat::from_extension(&out_path).unwrap();
+        let mut muxer: Box<dyn Muxer> = if write_to_file {
+            new_muxer(
+                muxer_format,
+                std::fs::File::create(&out_path).unwrap(),
+                VISIBLE_SIZE,
+                30,
+                FRAME_COUNT as u32,
+            )
+        } else {
+            new_muxer(muxer_format, io::sink(), VISIBLE_SIZE, 30, FRAME_COUNT as u32)
+        };
+        muxer.write_header().unwrap();
 
         let buffer_size = format
             .plane_fmt
@@ -205,23 +677,17 @@ mod tests {
         let mut frame_producer = userptr_test_frame_generator(FRAME_COUNT, layout, buffer_size);
 
         simple_encode_loop(&mut encoder, &mut frame_producer, |coded| {
-            let header = IvfFrameHeader {
-                timestamp: coded.metadata.timestamp,
-                frame_size: coded.bitstream.len() as u32,
-            };
-
-            header.writo_into(&mut bitstream).unwrap();
-            bitstream.extend(coded.bitstream);
+            muxer
+                .write_frame(
+                    &coded.bitstream,
+                    coded.metadata.timestamp,
+                    coded.metadata.keyframe,
+                )
+                .unwrap();
         })
         .expect("encode loop");
 
-        let write_to_file = std::option_env!("CROS_CODECS_TEST_WRITE_TO_FILE") == Some("true");
-        if write_to_file {
-            use std::io::Write;
-            let mut out = std::fs::File::create("test_v4l2_encoder_userptr.vp9.ivf").unwrap();
-            out.write_all(&bitstream).unwrap();
-            out.flush().unwrap();
-        }
+        muxer.finish().unwrap();
     }
 
     #[ignore]
@@ -252,43 +718,43 @@ mod tests {
                 ..Default::default()
             },
             Fourcc::from(b"NM12"),
+            Fourcc::from(b"NM12"),
             CODED_SIZE,
             Tunings {
                 rate_control: RateControl::ConstantBitrate(400_000),
                 ..Default::default()
             },
+            RateControlPass::SingleCbr,
         )
         .unwrap();
 
-        let mut bitstream = Vec::new();
-
-        let file_header = IvfFileHeader::new(
-            IvfFileHeader::CODEC_VP9,
-            VISIBLE_SIZE.width as u16,
-            VISIBLE_SIZE.height as u16,
-            30,
-            FRAME_COUNT as u32,
-        );
-
-        file_header.writo_into(&mut bitstream).unwrap();
+        let write_to_file = std::option_env!("CROS_CODECS_TEST_WRITE_TO_FILE") == Some("true");
+        let out_path = PathBuf::from("test_v4l2_encoder_mmap.vp9.ivf");
+        let muxer_format = ContainerFormat::from_extension(&out_path).unwrap();
+        let mut muxer: Box<dyn Muxer> = if write_to_file {
+            new_muxer(
+                muxer_format,
+                std::fs::File::create(&out_path).unwrap(),
+                VISIBLE_SIZE,
+                30,
+                FRAME_COUNT as u32,
+            )
+        } else {
+            new_muxer(muxer_format, io::sink(), VISIBLE_SIZE, 30, FRAME_COUNT as u32)
+        };
+        muxer.write_header().unwrap();
 
         perform_v4l2_encoder_mmap_test(FRAME_COUNT, encoder, |coded| {
-            let header = IvfFrameHeader {
-                timestamp: coded.metadata.timestamp,
-                frame_size: coded.bitstream.len() as u32,
-            };
-
-            header.writo_into(&mut bitstream).unwrap();
-            bitstream.extend(coded.bitstream);
+            muxer
+                .write_frame(
+                    &coded.bitstream,
+                    coded.metadata.timestamp,
+                    coded.metadata.keyframe,
+                )
+                .unwrap();
         });
 
-        let write_to_file = std::option_env!("CROS_CODECS_TEST_WRITE_TO_FILE") == Some("true");
-        if write_to_file {
-            use std::io::Write;
-            let mut out = std::fs::File::create("test_v4l2_encoder_mmap.vp9.ivf").unwrap();
-            out.write_all(&bitstream).unwrap();
-            out.flush().unwrap();
-        }
+        muxer.finish().unwrap();
     }
 
     #[ignore]
@@ -325,26 +791,32 @@ mod tests {
                 ..Default::default()
             },
             Fourcc::from(b"NV12"),
+            Fourcc::from(b"NV12"),
             CODED_SIZE,
             Tunings {
                 framerate: 30,
                 rate_control: RateControl::ConstantBitrate(400_000),
                 ..Default::default()
             },
+            RateControlPass::SingleCbr,
         )
         .unwrap();
 
-        let mut bitstream = Vec::new();
-
-        let file_header = IvfFileHeader::new(
-            IvfFileHeader::CODEC_VP9,
-            VISIBLE_SIZE.width as u16,
-            VISIBLE_SIZE.height as u16,
-            30,
-            FRAME_COUNT as u32,
-        );
-
-        file_header.writo_into(&mut bitstream).unwrap();
+        let write_to_file = std::option_env!("CROS_CODECS_TEST_WRITE_TO_FILE") == Some("true");
+        let out_path = PathBuf::from("test_v4l2_encoder_dmabuf.vp9.ivf");
+        let muxer_format = ContainerFormat::from_extension(&out_path).unwrap();
+        let mut muxer: Box<dyn Muxer> = if write_to_file {
+            new_muxer(
+                muxer_format,
+                std::fs::File::create(&out_path).unwrap(),
+                VISIBLE_SIZE,
+                30,
+                FRAME_COUNT as u32,
+            )
+        } else {
+            new_muxer(muxer_format, io::sink(), VISIBLE_SIZE, 30, FRAME_COUNT as u32)
+        };
+        muxer.write_header().unwrap();
 
         perform_v4l2_encoder_dmabuf_test(
             CODED_SIZE,
@@ -353,22 +825,16 @@ mod tests {
             gbm,
             encoder,
             |coded| {
-                let header = IvfFrameHeader {
-                    timestamp: coded.metadata.timestamp,
-                    frame_size: coded.bitstream.len() as u32,
-                };
-
-                header.writo_into(&mut bitstream).unwrap();
-                bitstream.extend(coded.bitstream);
+                muxer
+                    .write_frame(
+                        &coded.bitstream,
+                        coded.metadata.timestamp,
+                        coded.metadata.keyframe,
+                    )
+                    .unwrap();
             },
         );
 
-        let write_to_file = std::option_env!("CROS_CODECS_TEST_WRITE_TO_FILE") == Some("true");
-        if write_to_file {
-            use std::io::Write;
-            let mut out = std::fs::File::create("test_v4l2_encoder_dmabuf.vp9.ivf").unwrap();
-            out.write_all(&bitstream).unwrap();
-            out.flush().unwrap();
-        }
+        muxer.finish().unwrap();
     }
 }
@@ -0,0 +1,247 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Two-pass VBR support for the V4L2 stateful VP9 encoder: a first pass
+//! records per-frame size/QP stats at a fixed QP, and a second pass
+//! redistributes a bit budget across frames proportionally to the
+//! complexity those stats reveal.
+
+/// Selects which rate-control pass a [`super::V4L2VP9Backend`] session runs.
+pub enum RateControlPass {
+    /// Single encode pass at a constant bitrate/QP range.
+    SingleCbr,
+    /// Single encode pass at a variable bitrate/QP range.
+    SingleVbr,
+    /// The second pass of a two-pass VBR encode: QP decisions are derived
+    /// from `stats`, recorded by a prior first pass (possibly in another
+    /// process -- see [`FirstPassStats::to_bytes`]).
+    SecondPassFromStats(FirstPassStats),
+}
+
+/// Coarse VP9 frame kind, as recorded by the first pass. Only the
+/// distinction the bit-allocation heuristic cares about -- key frames are
+/// far more expensive per pixel than inter frames at the same QP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Key,
+    Inter,
+}
+
+/// One first-pass frame record: what it cost to encode at the first pass's
+/// fixed QP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub kind: FrameKind,
+    /// Size of the coded frame, in bits.
+    pub bits: u32,
+    pub qp: u8,
+}
+
+const STATS_MAGIC: u32 = 0x56_50_39_32; // "VP92"
+const STATS_VERSION: u16 = 1;
+// kind(1) + bits(4) + qp(1), matching the field order of `FrameStats`.
+const RECORD_SIZE: usize = 6;
+
+/// An ordered, serializable collection of first-pass [`FrameStats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FirstPassStats {
+    frames: Vec<FrameStats>,
+}
+
+impl FirstPassStats {
+    pub fn new() -> Self {
+        FirstPassStats { frames: Vec::new() }
+    }
+
+    /// Appends one frame's first-pass stats, in encode order.
+    pub fn record(&mut self, kind: FrameKind, bits: u32, qp: u8) {
+        self.frames.push(FrameStats { kind, bits, qp });
+    }
+
+    pub fn frames(&self) -> &[FrameStats] {
+        &self.frames
+    }
+
+    /// The global complexity metric this module's bit allocation is based
+    /// on: the sum, over every recorded frame, of its coded size in bits
+    /// times the QP it took to get there.
+    pub fn complexity(&self) -> u64 {
+        self.frames
+            .iter()
+            .map(|f| f.bits as u64 * f.qp as u64)
+            .sum()
+    }
+
+    /// Serializes the stats to a versioned, fixed-width-record byte buffer
+    /// so a first pass run in one process can hand its stats to a second
+    /// pass run in another.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(10 + self.frames.len() * RECORD_SIZE);
+        buf.extend_from_slice(&STATS_MAGIC.to_le_bytes());
+        buf.extend_from_slice(&STATS_VERSION.to_le_bytes());
+        buf.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+
+        for frame in &self.frames {
+            buf.push(match frame.kind {
+                FrameKind::Key => 0,
+                FrameKind::Inter => 1,
+            });
+            buf.extend_from_slice(&frame.bits.to_le_bytes());
+            buf.push(frame.qp);
+        }
+
+        buf
+    }
+
+    /// The inverse of [`Self::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 10 {
+            return Err("first-pass stats buffer too short for header".to_string());
+        }
+
+        let magic = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        if magic != STATS_MAGIC {
+            return Err(format!("bad first-pass stats magic: {:#x}", magic));
+        }
+
+        let version = u16::from_le_bytes(data[4..6].try_into().unwrap());
+        if version != STATS_VERSION {
+            return Err(format!("unsupported first-pass stats version: {}", version));
+        }
+
+        let count = u32::from_le_bytes(data[6..10].try_into().unwrap()) as usize;
+        let expected_len = 10 + count * RECORD_SIZE;
+        if data.len() != expected_len {
+            return Err(format!(
+                "first-pass stats buffer has {} bytes, expected {} for {} records",
+                data.len(),
+                expected_len,
+                count
+            ));
+        }
+
+        let mut frames = Vec::with_capacity(count);
+        for record in data[10..].chunks_exact(RECORD_SIZE) {
+            let kind = match record[0] {
+                0 => FrameKind::Key,
+                1 => FrameKind::Inter,
+                other => return Err(format!("bad first-pass frame kind: {}", other)),
+            };
+            let bits = u32::from_le_bytes(record[1..5].try_into().unwrap());
+            let qp = record[5];
+            frames.push(FrameStats { kind, bits, qp });
+        }
+
+        Ok(FirstPassStats { frames })
+    }
+}
+
+/// Walks a [`FirstPassStats`] recording and hands back a target QP per
+/// frame for the second pass, redistributing `total_bits_budget` across
+/// frames proportionally to each one's share of the recorded complexity.
+pub struct TwoPassRateControl {
+    stats: FirstPassStats,
+    complexity: u64,
+    total_bits_budget: u64,
+    cursor: usize,
+}
+
+impl TwoPassRateControl {
+    /// `target_bitrate` and `duration_secs` together give the total bit
+    /// budget for the sequence (`target_bitrate * duration_secs`), which is
+    /// then spread across frames by [`Self::next_qp`].
+    pub fn new(stats: FirstPassStats, target_bitrate: u64, duration_secs: f64) -> Self {
+        let complexity = stats.complexity().max(1);
+        let total_bits_budget = (target_bitrate as f64 * duration_secs) as u64;
+
+        TwoPassRateControl {
+            stats,
+            complexity,
+            total_bits_budget,
+            cursor: 0,
+        }
+    }
+
+    /// Returns the target QP for the next frame in encode order, clamped to
+    /// `[min_quality, max_quality]`, and advances the cursor. Panics if
+    /// called more times than there are recorded frames -- the second pass
+    /// must encode exactly the sequence the first pass measured.
+    pub fn next_qp(&mut self, min_quality: u8, max_quality: u8) -> u8 {
+        let frame = self.stats.frames()[self.cursor];
+        self.cursor += 1;
+
+        let frame_complexity = frame.bits as u64 * frame.qp as u64;
+        let share = frame_complexity as f64 / self.complexity as f64;
+        let allotted_bits = (self.total_bits_budget as f64 * share).max(1.0);
+
+        // More bits than the first pass used for this frame means a lower
+        // QP can afford the same content; fewer bits means a higher one.
+        // This is a linear approximation of the rate/QP curve, not a real
+        // rate-distortion model -- good enough to bias the distribution
+        // without a second encode pass's worth of statistics to fit one.
+        let scale = frame.bits as f64 / allotted_bits;
+        let target_qp = (frame.qp as f64 * scale).round();
+
+        target_qp.clamp(min_quality as f64, max_quality as f64) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_roundtrip_through_bytes() {
+        let mut stats = FirstPassStats::new();
+        stats.record(FrameKind::Key, 40_000, 32);
+        stats.record(FrameKind::Inter, 8_000, 48);
+        stats.record(FrameKind::Inter, 6_500, 50);
+
+        let bytes = stats.to_bytes();
+        let decoded = FirstPassStats::from_bytes(&bytes).expect("failed to decode stats");
+
+        assert_eq!(decoded, stats);
+    }
+
+    #[test]
+    fn from_bytes_rejects_bad_magic() {
+        let mut bytes = FirstPassStats::new().to_bytes();
+        bytes[0] ^= 0xff;
+        assert!(FirstPassStats::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn from_bytes_rejects_truncated_records() {
+        let mut stats = FirstPassStats::new();
+        stats.record(FrameKind::Key, 1000, 20);
+        let mut bytes = stats.to_bytes();
+        bytes.pop();
+        assert!(FirstPassStats::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn complexity_is_sum_of_bits_times_qp() {
+        let mut stats = FirstPassStats::new();
+        stats.record(FrameKind::Key, 100, 10);
+        stats.record(FrameKind::Inter, 50, 20);
+
+        assert_eq!(stats.complexity(), 100 * 10 + 50 * 20);
+    }
+
+    #[test]
+    fn second_pass_qp_is_clamped_to_quality_range() {
+        let mut stats = FirstPassStats::new();
+        // A very cheap frame relative to the sequence's complexity gets a
+        // tiny bit allotment, which should clamp to the max QP rather than
+        // diverge.
+        stats.record(FrameKind::Key, 100_000, 20);
+        stats.record(FrameKind::Inter, 10, 20);
+
+        let mut rc = TwoPassRateControl::new(stats, 8_000, 1.0);
+        let _ = rc.next_qp(0, 63);
+        let qp = rc.next_qp(0, 63);
+
+        assert_eq!(qp, 63);
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Container muxers for coded bitstreams produced by this crate's encoders.
+//!
+//! Every caller of [`crate::encoder::simple_encode_loop`] used to hand-roll
+//! IVF framing around its output `Vec<u8>`. [`Muxer`] pulls that framing out
+//! into a small trait with one implementation per container format, so a
+//! caller only has to pick a format (by [`ContainerFormat::from_extension`]
+//! or directly) and feed it frames.
+
+mod ivf;
+mod webm;
+
+pub use ivf::IvfMuxer;
+pub use webm::WebmMuxer;
+
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use crate::Resolution;
+
+/// A container format a [`Muxer`] can write coded VP9 frames into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerFormat {
+    /// The raw IVF framing this crate's tests have always used.
+    Ivf,
+    /// WebM (a Matroska profile), for playback and web delivery.
+    WebM,
+}
+
+impl ContainerFormat {
+    /// Picks a format from a file's extension, for callers that just want to
+    /// write to a path and not think about containers any further.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "ivf" => Some(Self::Ivf),
+            "webm" | "mkv" => Some(Self::WebM),
+            _ => None,
+        }
+    }
+}
+
+/// Common interface every container muxer in this module implements.
+///
+/// Callers drive it as: construct, [`Muxer::write_header`] once,
+/// [`Muxer::write_frame`] once per coded frame in encode order, then
+/// [`Muxer::finish`]. Implementations are free to defer all actual I/O to
+/// `finish` if the format needs trailer information (e.g. a duration) that
+/// isn't known until every frame has been seen.
+pub trait Muxer {
+    /// Writes (or queues, see above) whatever file-level header this
+    /// container needs. Must be called exactly once, before any
+    /// `write_frame` call.
+    fn write_header(&mut self) -> io::Result<()>;
+
+    /// Appends one coded frame, in encode order. `timestamp` is the coded
+    /// frame's presentation timestamp; `keyframe` is taken from the coded
+    /// frame's metadata and drives container-level indexing (e.g. a WebM
+    /// SimpleBlock's keyframe flag).
+    fn write_frame(&mut self, bitstream: &[u8], timestamp: u64, keyframe: bool)
+        -> io::Result<()>;
+
+    /// Finalizes the container and flushes it to the underlying writer.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Builds the [`Muxer`] for `format`, writing a VP9 track sized to
+/// `resolution` at `framerate` fps into `writer`.
+pub fn new_muxer<W: Write + 'static>(
+    format: ContainerFormat,
+    writer: W,
+    resolution: Resolution,
+    framerate: u32,
+    frame_count: u32,
+) -> Box<dyn Muxer> {
+    match format {
+        ContainerFormat::Ivf => Box::new(IvfMuxer::new(writer, resolution, framerate, frame_count)),
+        ContainerFormat::WebM => Box::new(WebmMuxer::new(writer, resolution, framerate)),
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The IVF implementation of [`super::Muxer`], a thin wrapper around the
+//! existing [`IvfFileHeader`]/[`IvfFrameHeader`] writers.
+
+use std::io;
+use std::io::Write;
+
+use super::Muxer;
+use crate::utils::IvfFileHeader;
+use crate::utils::IvfFrameHeader;
+use crate::Resolution;
+
+/// Streams frames straight through to `writer` as they arrive -- IVF has no
+/// trailer and doesn't need a frame's keyframe flag, so there's nothing to
+/// buffer.
+pub struct IvfMuxer<W: Write> {
+    writer: W,
+    file_header: IvfFileHeader,
+}
+
+impl<W: Write> IvfMuxer<W> {
+    pub fn new(writer: W, resolution: Resolution, framerate: u32, frame_count: u32) -> Self {
+        let file_header = IvfFileHeader::new(
+            IvfFileHeader::CODEC_VP9,
+            resolution.width as u16,
+            resolution.height as u16,
+            framerate,
+            frame_count,
+        );
+
+        IvfMuxer {
+            writer,
+            file_header,
+        }
+    }
+}
+
+impl<W: Write> Muxer for IvfMuxer<W> {
+    fn write_header(&mut self) -> io::Result<()> {
+        self.file_header.writo_into(&mut self.writer)
+    }
+
+    fn write_frame(
+        &mut self,
+        bitstream: &[u8],
+        timestamp: u64,
+        _keyframe: bool,
+    ) -> io::Result<()> {
+        let frame_header = IvfFrameHeader {
+            timestamp,
+            frame_size: bitstream.len() as u32,
+        };
+
+        frame_header.writo_into(&mut self.writer)?;
+        self.writer.write_all(bitstream)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The WebM (a Matroska profile) implementation of [`super::Muxer`].
+//!
+//! Unlike IVF, WebM's `Info` element carries a `Duration` and its
+//! `SeekHead` carries byte offsets into the file -- neither is known until
+//! every frame has been seen, and `W: Write` gives us no way to seek back
+//! and patch them in. So this muxer buffers every frame's bytes internally
+//! and defers all EBML emission to [`Muxer::finish`], once the duration and
+//! element layout are fully known.
+
+use std::io;
+use std::io::Write;
+
+use super::Muxer;
+use crate::Resolution;
+
+// Well-known Matroska/WebM element IDs (ITU-T/Matroska spec), listed in the
+// order they're written below.
+const ID_EBML: u32 = 0x1A45_DFA3;
+const ID_EBML_VERSION: u32 = 0x4286;
+const ID_EBML_READ_VERSION: u32 = 0x42F7;
+const ID_EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const ID_EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const ID_DOC_TYPE: u32 = 0x4282;
+const ID_DOC_TYPE_VERSION: u32 = 0x4287;
+const ID_DOC_TYPE_READ_VERSION: u32 = 0x4285;
+
+const ID_SEGMENT: u32 = 0x1853_8067;
+const ID_SEEK_HEAD: u32 = 0x114D_9B74;
+const ID_SEEK: u32 = 0x4DBB;
+const ID_SEEK_ID: u32 = 0x53AB;
+const ID_SEEK_POSITION: u32 = 0x53AC;
+
+const ID_INFO: u32 = 0x1549_A966;
+const ID_TIMECODE_SCALE: u32 = 0x2AD7_B1;
+const ID_DURATION: u32 = 0x4489;
+const ID_MUXING_APP: u32 = 0x4D80;
+const ID_WRITING_APP: u32 = 0x5741;
+
+const ID_TRACKS: u32 = 0x1654_AE6B;
+const ID_TRACK_ENTRY: u32 = 0xAE;
+const ID_TRACK_NUMBER: u32 = 0xD7;
+const ID_TRACK_UID: u32 = 0x73C5;
+const ID_TRACK_TYPE: u32 = 0x83;
+const ID_CODEC_ID: u32 = 0x86;
+const ID_VIDEO: u32 = 0xE0;
+const ID_PIXEL_WIDTH: u32 = 0xB0;
+const ID_PIXEL_HEIGHT: u32 = 0xBA;
+
+const ID_CLUSTER: u32 = 0x1F43_B675;
+const ID_TIMECODE: u32 = 0xE7;
+const ID_SIMPLE_BLOCK: u32 = 0xA3;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const VP9_TRACK_NUMBER: u64 = 1;
+
+/// Matroska timestamps are counted in units of `TimecodeScale` nanoseconds;
+/// one millisecond keeps the arithmetic simple while staying well within
+/// every player's expectations.
+const TIMECODE_SCALE_NS: u64 = 1_000_000;
+
+fn encode_vint(value: u64) -> Vec<u8> {
+    let mut length = 1u32;
+    while length < 8 && value >= (1u64 << (length * 7)) - 1 {
+        length += 1;
+    }
+    let marker = 1u64 << (length * 7);
+    let encoded = marker | value;
+    encoded.to_be_bytes()[(8 - length as usize)..].to_vec()
+}
+
+fn write_id(out: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(3);
+    out.extend_from_slice(&bytes[start..]);
+}
+
+fn write_size(out: &mut Vec<u8>, size: u64) {
+    out.extend_from_slice(&encode_vint(size));
+}
+
+/// Writes a complete element: id, size, then `body` verbatim.
+fn write_element(out: &mut Vec<u8>, id: u32, body: &[u8]) {
+    write_id(out, id);
+    write_size(out, body.len() as u64);
+    out.extend_from_slice(body);
+}
+
+fn uint_body(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(7);
+    bytes[start..].to_vec()
+}
+
+fn write_uint(out: &mut Vec<u8>, id: u32, value: u64) {
+    write_element(out, id, &uint_body(value));
+}
+
+fn write_float(out: &mut Vec<u8>, id: u32, value: f64) {
+    write_element(out, id, &value.to_be_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, id: u32, value: &str) {
+    write_element(out, id, value.as_bytes());
+}
+
+fn ebml_header() -> Vec<u8> {
+    let mut body = Vec::new();
+    write_uint(&mut body, ID_EBML_VERSION, 1);
+    write_uint(&mut body, ID_EBML_READ_VERSION, 1);
+    write_uint(&mut body, ID_EBML_MAX_ID_LENGTH, 4);
+    write_uint(&mut body, ID_EBML_MAX_SIZE_LENGTH, 8);
+    write_str(&mut body, ID_DOC_TYPE, "webm");
+    write_uint(&mut body, ID_DOC_TYPE_VERSION, 2);
+    write_uint(&mut body, ID_DOC_TYPE_READ_VERSION, 2);
+
+    let mut out = Vec::new();
+    write_element(&mut out, ID_EBML, &body);
+    out
+}
+
+/// One buffered coded frame, recorded so [`Muxer::finish`] can lay out
+/// clusters once the whole sequence is known.
+struct BufferedFrame {
+    bitstream: Vec<u8>,
+    timestamp_ms: u64,
+    keyframe: bool,
+}
+
+pub struct WebmMuxer<W: Write> {
+    writer: W,
+    resolution: Resolution,
+    framerate: u32,
+    frames: Vec<BufferedFrame>,
+    header_written: bool,
+}
+
+impl<W: Write> WebmMuxer<W> {
+    pub fn new(writer: W, resolution: Resolution, framerate: u32) -> Self {
+        WebmMuxer {
+            writer,
+            resolution,
+            framerate,
+            frames: Vec::new(),
+            header_written: false,
+        }
+    }
+
+    fn info_element(&self) -> Vec<u8> {
+        let duration_timecodes = match self.frames.last() {
+            Some(last) => (last.timestamp_ms + 1000 / self.framerate.max(1) as u64) as f64,
+            None => 0.0,
+        };
+
+        let mut body = Vec::new();
+        write_uint(&mut body, ID_TIMECODE_SCALE, TIMECODE_SCALE_NS);
+        write_float(&mut body, ID_DURATION, duration_timecodes);
+        write_str(&mut body, ID_MUXING_APP, "cros-codecs");
+        write_str(&mut body, ID_WRITING_APP, "cros-codecs");
+
+        let mut out = Vec::new();
+        write_element(&mut out, ID_INFO, &body);
+        out
+    }
+
+    fn tracks_element(&self) -> Vec<u8> {
+        let mut video = Vec::new();
+        write_uint(&mut video, ID_PIXEL_WIDTH, self.resolution.width as u64);
+        write_uint(&mut video, ID_PIXEL_HEIGHT, self.resolution.height as u64);
+
+        let mut track_entry = Vec::new();
+        write_uint(&mut track_entry, ID_TRACK_NUMBER, VP9_TRACK_NUMBER);
+        write_uint(&mut track_entry, ID_TRACK_UID, VP9_TRACK_NUMBER);
+        write_uint(&mut track_entry, ID_TRACK_TYPE, TRACK_TYPE_VIDEO);
+        write_str(&mut track_entry, ID_CODEC_ID, "V_VP9");
+        write_element(&mut track_entry, ID_VIDEO, &video);
+
+        let mut tracks = Vec::new();
+        write_element(&mut tracks, ID_TRACK_ENTRY, &track_entry);
+
+        let mut out = Vec::new();
+        write_element(&mut out, ID_TRACKS, &tracks);
+        out
+    }
+
+    /// One `SeekHead` entry each for `Info` and `Tracks` (clusters are not
+    /// listed), pointing at its offset
+    /// from the start of the `Segment`'s own payload (as Matroska requires).
+    fn seek_head_element(&self, info_offset: u64, tracks_offset: u64) -> Vec<u8> {
+        let seek_entry = |id: u32, position: u64| -> Vec<u8> {
+            let mut id_bytes = Vec::new();
+            write_id(&mut id_bytes, id);
+
+            let mut body = Vec::new();
+            write_element(&mut body, ID_SEEK_ID, &id_bytes);
+            write_uint(&mut body, ID_SEEK_POSITION, position);
+
+            let mut entry = Vec::new();
+            write_element(&mut entry, ID_SEEK, &body);
+            entry
+        };
+
+        let mut body = Vec::new();
+        body.extend(seek_entry(ID_INFO, info_offset));
+        body.extend(seek_entry(ID_TRACKS, tracks_offset));
+
+        let mut out = Vec::new();
+        write_element(&mut out, ID_SEEK_HEAD, &body);
+        out
+    }
+
+    /// Lays every buffered frame out into one `Cluster` per keyframe (plus
+    /// whatever inter frames follow it, matching how real WebM encoders
+    /// group clusters), so players can seek to any keyframe's cluster
+    /// boundary.
+    fn cluster_elements(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut current_cluster_timecode: Option<u64> = None;
+        let mut cluster_body = Vec::new();
+
+        let flush = |out: &mut Vec<u8>, timecode: u64, body: &[u8]| {
+            let mut cluster = Vec::new();
+            write_uint(&mut cluster, ID_TIMECODE, timecode);
+            cluster.extend_from_slice(body);
+            write_element(out, ID_CLUSTER, &cluster);
+        };
+
+        for frame in &self.frames {
+            if frame.keyframe || current_cluster_timecode.is_none() {
+                if let Some(timecode) = current_cluster_timecode {
+                    flush(&mut out, timecode, &cluster_body);
+                    cluster_body.clear();
+                }
+                current_cluster_timecode = Some(frame.timestamp_ms);
+            }
+
+            let cluster_timecode = current_cluster_timecode.unwrap();
+            let relative_timecode = (frame.timestamp_ms - cluster_timecode) as i16;
+
+            // SimpleBlock body: track number (as a VINT), a signed 16-bit
+            // relative timecode, one flags byte (bit 7 set for keyframes),
+            // then the raw frame bytes.
+            let mut block = Vec::new();
+            block.extend(encode_vint(VP9_TRACK_NUMBER));
+            block.extend_from_slice(&relative_timecode.to_be_bytes());
+            block.push(if frame.keyframe { 0x80 } else { 0x00 });
+            block.extend_from_slice(&frame.bitstream);
+
+            write_element(&mut cluster_body, ID_SIMPLE_BLOCK, &block);
+        }
+
+        if let Some(timecode) = current_cluster_timecode {
+            flush(&mut out, timecode, &cluster_body);
+        }
+
+        out
+    }
+}
+
+impl<W: Write> Muxer for WebmMuxer<W> {
+    fn write_header(&mut self) -> io::Result<()> {
+        // Nothing to write yet: the Info/SeekHead/Cluster layout all depend
+        // on frames this muxer hasn't seen. See the module doc comment.
+        self.header_written = true;
+        Ok(())
+    }
+
+    fn write_frame(
+        &mut self,
+        bitstream: &[u8],
+        timestamp: u64,
+        keyframe: bool,
+    ) -> io::Result<()> {
+        debug_assert!(self.header_written, "write_header must be called first");
+
+        self.frames.push(BufferedFrame {
+            bitstream: bitstream.to_vec(),
+            timestamp_ms: timestamp / (TIMECODE_SCALE_NS / 1000).max(1),
+            keyframe,
+        });
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let info = self.info_element();
+        let tracks = self.tracks_element();
+        let clusters = self.cluster_elements();
+
+        // SeekHead positions are relative to the first byte after the
+        // Segment element's own id+size, which is exactly where the
+        // SeekHead itself starts -- so its own length has to be folded into
+        // the offsets it's about to describe. `seek_head_element` is
+        // written twice: once to measure its encoded length, once with that
+        // length accounted for in the offsets that follow it.
+        let placeholder_seek_head = self.seek_head_element(0, 0);
+        let seek_head_len = placeholder_seek_head.len() as u64;
+        let info_offset = seek_head_len;
+        let tracks_offset = info_offset + info.len() as u64;
+        let seek_head = self.seek_head_element(info_offset, tracks_offset);
+        debug_assert_eq!(seek_head.len() as u64, seek_head_len);
+
+        let mut segment_body = Vec::new();
+        segment_body.extend(seek_head);
+        segment_body.extend(info);
+        segment_body.extend(tracks);
+        segment_body.extend(clusters);
+
+        let mut out = ebml_header();
+        write_element(&mut out, ID_SEGMENT, &segment_body);
+
+        self.writer.write_all(&out)?;
+        self.writer.flush()
+    }
+}
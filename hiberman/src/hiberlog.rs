@@ -3,6 +3,10 @@
 // found in the LICENSE file.
 
 //! Implement consistent logging across the hibernate and resume transition.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::ffi::CString;
 use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
@@ -11,11 +15,16 @@ use std::io::BufReader;
 use std::io::Cursor;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::panic;
+use std::panic::PanicHookInfo;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::abort;
 use std::str;
 use std::sync::Mutex;
 use std::sync::MutexGuard;
+use std::time::Duration;
 use std::time::Instant;
 
 use anyhow::anyhow;
@@ -28,9 +37,7 @@ use log::Log;
 use log::Metadata;
 use log::Record;
 use once_cell::sync::OnceCell;
-use syslog::BasicLogger;
 use syslog::Facility;
-use syslog::Formatter3164;
 
 use crate::files::HIBERMETA_DIR;
 use crate::hiberutil::HibernateStage;
@@ -41,12 +48,25 @@ use crate::volume::ActiveMount;
 const KMSG_PATH: &str = "/dev/kmsg";
 /// Define the prefix to go on log messages.
 const LOG_PREFIX: &str = "hiberman";
+/// NUL-terminated identity string passed to `openlog(3)`.
+const LOG_IDENT: &[u8] = b"hiberman\0";
+/// Path to the local syslog socket. Used as a cheap reachability check before connecting: unlike
+/// a Unix datagram socket, `libc::syslog` doesn't report failure if nothing is listening, so
+/// without this check a missing daemon would silently drop every log line instead of falling
+/// back to kmsg.
+const SYSLOG_SOCKET_PATH: &str = "/dev/log";
 
 /// Define the name of the resume log file.
 const RESUME_LOG_FILE_NAME: &str = "resume_log";
 /// Define the name of the suspend log file.
 const SUSPEND_LOG_FILE_NAME: &str = "suspend_log";
 
+/// Maximum number of log lines kept in the in-memory `pending` buffer. Hibernate/resume can spend
+/// an unpredictable amount of time with logs buffered in memory (e.g. while `hibermeta` is
+/// unavailable), so without a cap a chatty stretch of logging could grow this buffer without
+/// bound; past this many lines, the oldest ones are dropped to make room for new ones.
+const MAX_PENDING_LOG_LINES: usize = 2000;
+
 static STATE: OnceCell<Mutex<Hiberlog>> = OnceCell::new();
 
 fn get_state() -> Result<&'static Mutex<Hiberlog>> {
@@ -114,26 +134,44 @@ pub enum HiberlogOut {
 struct Hiberlog {
     kmsg: File,
     start: Instant,
-    pending: Vec<Vec<u8>>,
+    /// Ring buffer of not-yet-flushed log lines: bounded at [`MAX_PENDING_LOG_LINES`], oldest
+    /// entries evicted first once full.
+    pending: VecDeque<Vec<u8>>,
+    /// Number of lines evicted from `pending` since the last flush, surfaced as a notice line the
+    /// next time `pending` is flushed.
+    dropped: u64,
     to_kmsg: bool,
     out: HiberlogOut,
     pid: u32,
-    syslogger: BasicLogger,
+    /// Connection to the local syslog daemon, or `None` if one wasn't reachable (e.g. very early
+    /// in boot, or in the bootstrapping resume kernel). Lines normally destined for syslog fall
+    /// back to kmsg instead of being lost when this is `None`.
+    syslogger: Option<PosixSyslog>,
 }
 
 impl Hiberlog {
     pub fn new() -> Result<Self> {
-        let kmsg = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(KMSG_PATH)
-            .context("Failed to open kernel message logger")?;
+        let kmsg = open_kmsg()?;
+
+        let syslogger = match create_syslogger() {
+            Ok(syslogger) => Some(syslogger),
+            Err(e) => {
+                let _ = writeln!(
+                    &kmsg,
+                    "<{}>{}: {}, falling back to kmsg",
+                    priority_from_level(Level::Warn) + (Facility::LOG_USER as usize),
+                    LOG_PREFIX,
+                    e
+                );
+                None
+            }
+        };
 
-        let syslogger = create_syslogger();
         Ok(Hiberlog {
             kmsg,
             start: Instant::now(),
-            pending: vec![],
+            pending: VecDeque::new(),
+            dropped: 0,
             to_kmsg: false,
             out: HiberlogOut::Syslog,
             pid: std::process::id(),
@@ -141,58 +179,59 @@ impl Hiberlog {
         })
     }
 
+    /// Appends `line` to `pending`, evicting the oldest entry (and counting it in `dropped`) if
+    /// the buffer is already at [`MAX_PENDING_LOG_LINES`].
+    fn push_pending(&mut self, line: Vec<u8>) {
+        if self.pending.len() >= MAX_PENDING_LOG_LINES {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+        self.pending.push_back(line);
+    }
+
     /// Log a record.
     fn log_record(&mut self, record: &Record) {
-        let mut buf = [0u8; 1024];
-
-        // If sending to the syslog, just forward there and exit.
+        // If sending to the syslog, just forward there (or to kmsg, if no syslog daemon is
+        // reachable) and exit.
         if matches!(self.out, HiberlogOut::Syslog) {
-            self.syslogger.log(record);
+            log_record_to(&self.syslogger, &mut self.kmsg, self.pid, record);
             return;
         }
 
-        let res = {
-            let mut buf_cursor = Cursor::new(&mut buf[..]);
-            let facprio = priority_from_level(record.level()) + (Facility::LOG_USER as usize);
-            if let Some(file) = record.file() {
-                let duration = self.start.elapsed();
-                write!(
-                    &mut buf_cursor,
-                    "<{}>{}: {}.{:03} {} [{}:{}] ",
-                    facprio,
-                    LOG_PREFIX,
-                    duration.as_secs(),
-                    duration.subsec_millis(),
-                    self.pid,
-                    file,
-                    record.line().unwrap_or(0)
-                )
-            } else {
-                write!(&mut buf_cursor, "<{}>{}: ", facprio, LOG_PREFIX)
-            }
-            .and_then(|()| writeln!(&mut buf_cursor, "{}", record.args()))
-            .map(|()| buf_cursor.position() as usize)
-        };
+        let mut buf = [0u8; 1024];
+        let res = format_kmsg_record(&mut buf, self.pid, self.start, record);
 
-        if let Ok(len) = &res {
+        if let Ok(len) = res {
             if self.to_kmsg {
-                let _ = self.kmsg.write_all(&buf[..*len]);
+                let _ = self.kmsg.write_all(&buf[..len]);
             }
 
             if let HiberlogOut::File(f) = &mut self.out {
-                let _ = f.write_all(&buf[..*len]);
+                let _ = f.write_all(&buf[..len]);
             } else {
-                self.pending.push(buf[..*len].to_vec());
+                self.push_pending(buf[..len].to_vec());
             }
         }
     }
 
     fn flush(&mut self) {
+        if self.dropped > 0 {
+            let facprio = priority_from_level(Level::Warn) + (Facility::LOG_USER as usize);
+            let notice = format!(
+                "<{}>{}: {} pending log line(s) dropped (buffer full)",
+                facprio, LOG_PREFIX, self.dropped
+            );
+            self.pending.push_front(notice.into_bytes());
+            self.dropped = 0;
+        }
+
         match &mut self.out {
             // Write any ending lines to the file.
             HiberlogOut::File(f) => {
                 // self.pending will be empty if previously not logging to memory.
-                if self.pending.is_empty() { return; }
+                if self.pending.is_empty() {
+                    return;
+                }
                 map_log_entries(&self.pending, |s| {
                     let _ = f.write_all(&[s, &[b'\n']].concat());
                 });
@@ -200,8 +239,11 @@ impl Hiberlog {
             }
             // Push any pending lines to the syslog.
             HiberlogOut::Syslog => {
+                let syslogger = &self.syslogger;
+                let kmsg = &mut self.kmsg;
+                let pid = self.pid;
                 map_log_entries(&self.pending, |s| {
-                    replay_line(&self.syslogger, "M", s);
+                    replay_line(syslogger, kmsg, pid, "M", s);
                 });
                 self.reset();
             }
@@ -216,11 +258,102 @@ impl Hiberlog {
     /// got flushed after the snapshot was taken, just before the machine shut
     /// down.
     pub fn reset(&mut self) {
-        self.pending = vec![];
+        self.pending = VecDeque::new();
+        self.dropped = 0;
+    }
+
+    /// Unconditionally writes `message` to kmsg and, if a stage log file is currently active, to
+    /// that file too -- regardless of the normal `to_kmsg`/`out` routing a logged record would
+    /// otherwise follow. Used by the panic handler, which is about to abort the process and can't
+    /// count on a later flush ever happening.
+    fn log_panic(&mut self, message: &str) {
+        let mut buf = [0u8; 1024];
+        let len = format_panic_line(message, &mut buf);
+        if len == 0 {
+            return;
+        }
+
+        let _ = self.kmsg.write_all(&buf[..len]);
+
+        if let HiberlogOut::File(f) = &mut self.out {
+            let _ = f.write_all(&buf[..len]);
+        }
+    }
+}
+
+/// Formats `message` as a panic line into `buf` (the same format [`Hiberlog::log_panic`] writes),
+/// returning how many bytes were written.
+fn format_panic_line(message: &str, buf: &mut [u8]) -> usize {
+    let mut buf_cursor = Cursor::new(buf);
+    let facprio = priority_from_level(Level::Error) + (Facility::LOG_USER as usize);
+    write!(&mut buf_cursor, "<{}>{}: PANIC ", facprio, LOG_PREFIX)
+        .and_then(|()| writeln!(&mut buf_cursor, "{}", message))
+        .map(|()| buf_cursor.position() as usize)
+        .unwrap_or(0)
+}
+
+/// Writes `message` directly to a freshly opened kmsg handle, bypassing `Hiberlog::log_panic()`
+/// and the `STATE` mutex entirely. Used by the panic hook when the mutex can't be acquired without
+/// risking blocking forever.
+fn raw_kmsg_panic_write(message: &str) {
+    let Ok(mut kmsg) = open_kmsg() else {
+        return;
+    };
+
+    let mut buf = [0u8; 1024];
+    let len = format_panic_line(message, &mut buf);
+    if len > 0 {
+        let _ = kmsg.write_all(&buf[..len]);
     }
 }
 
-fn map_log_entries<F>(entries: &[Vec<u8>], mut f: F)
+/// Formats a panic's payload and location into a single line, e.g.
+/// `"oh no" at src/resume.rs:42:5`.
+fn format_panic_payload(info: &PanicHookInfo<'_>) -> String {
+    let payload = info
+        .payload()
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string());
+
+    match info.location() {
+        Some(location) => format!("{:?} at {}", payload, location),
+        None => format!("{:?}", payload),
+    }
+}
+
+/// Installs a panic hook that routes the panic payload through this module's logger -- reaching
+/// kmsg and the currently active stage log file -- before aborting the process.
+///
+/// A panic during hibernate/resume can crash the machine before a regular crash report ever gets
+/// collected, and a plain panic's default output only goes to stderr, which nothing reads this
+/// early in boot. Logging through [`Hiberlog::log_panic`] instead guarantees the payload lands
+/// somewhere durable: kmsg always, and whichever `suspend_log`/`resume_log` file is active, if
+/// any.
+///
+/// Uses `try_lock()` rather than `log::error!()`/`lock()`: both of those block on the same `STATE`
+/// mutex that `HiberLogger::log()`/`redirect_log`/`reset_log`/`flush` take, so a panic occurring
+/// while this thread already holds it (e.g. from inside `log_record()` or `flush()`) would
+/// otherwise deadlock the hook against itself, turning a guaranteed "log to kmsg, then abort" into
+/// a silent hang. When the lock isn't available, falls back to [`raw_kmsg_panic_write`], which
+/// writes straight to a fresh kmsg handle without touching the mutex at all.
+pub fn install_panic_handler() {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let message = format_panic_payload(info);
+
+        match STATE.get().and_then(|state| state.try_lock().ok()) {
+            Some(mut state) => state.log_panic(&message),
+            None => raw_kmsg_panic_write(&message),
+        }
+
+        hook(info);
+        abort();
+    }));
+}
+
+fn map_log_entries<F>(entries: &VecDeque<Vec<u8>>, mut f: F)
 where
     F: FnMut(&[u8]),
 {
@@ -376,8 +509,26 @@ fn replay_log_file(stage: HibernateStage, clear: bool) {
         HibernateStage::Resume => ("resume log", "R"),
     };
 
-    let syslogger = create_syslogger();
-    syslogger.log(
+    let syslogger = match create_syslogger() {
+        Ok(syslogger) => Some(syslogger),
+        Err(e) => {
+            warn!("{}, falling back to kmsg while replaying {}", e, name);
+            None
+        }
+    };
+    let mut kmsg = match open_kmsg() {
+        Ok(kmsg) => kmsg,
+        Err(e) => {
+            warn!("{}", e);
+            return;
+        }
+    };
+    let pid = std::process::id();
+
+    log_record_to(
+        &syslogger,
+        &mut kmsg,
+        pid,
         &Record::builder()
             .args(format_args!("Replaying {}:", name))
             .level(Level::Info)
@@ -395,13 +546,16 @@ fn replay_log_file(stage: HibernateStage, clear: bool) {
     let reader = BufReader::new(f);
     for line in reader.lines() {
         if let Ok(line) = line {
-            replay_line(&syslogger, prefix, line.as_bytes());
+            replay_line(&syslogger, &mut kmsg, pid, prefix, line.as_bytes());
         } else {
             warn!("Invalid line in log file!");
         }
     }
 
-    syslogger.log(
+    log_record_to(
+        &syslogger,
+        &mut kmsg,
+        pid,
         &Record::builder()
             .args(format_args!("Done replaying {}", name))
             .level(Level::Info)
@@ -413,8 +567,9 @@ fn replay_log_file(stage: HibernateStage, clear: bool) {
     }
 }
 
-/// Replay a single log line to the syslogger.
-fn replay_line(syslogger: &BasicLogger, prefix: &str, s: &[u8]) {
+/// Replay a single log line to the syslogger, falling back to kmsg if no syslog daemon is
+/// reachable.
+fn replay_line(syslogger: &Option<PosixSyslog>, kmsg: &mut File, pid: u32, prefix: &str, s: &[u8]) {
     // The log lines are in kmsg format, like:
     // <11>hiberman: R [src/hiberman.rs:529] Hello 2004
     // Trim off the first colon, everything after is line contents.
@@ -426,10 +581,27 @@ fn replay_line(syslogger: &BasicLogger, prefix: &str, s: &[u8]) {
     }
 
     match parse_rfc3164_record(line) {
-        Ok((contents, level)) => {
-            syslogger.log(
+        Ok((contents, level, origin)) => {
+            let message = match origin {
+                // Carry the original relative timing forward instead of letting the replayed
+                // record pick up today's wall-clock time, which would collapse the real timeline
+                // of events that happened while syslog was frozen.
+                Some((elapsed, orig_pid)) => format!(
+                    "{} +{}.{:03} (pid {}) {}",
+                    prefix,
+                    elapsed.as_secs(),
+                    elapsed.subsec_millis(),
+                    orig_pid,
+                    contents
+                ),
+                None => format!("{} {}", prefix, contents),
+            };
+            log_record_to(
+                syslogger,
+                kmsg,
+                pid,
                 &Record::builder()
-                    .args(format_args!("{} {}", prefix, contents))
+                    .args(format_args!("{}", message))
                     .level(level)
                     .build(),
             );
@@ -440,7 +612,7 @@ fn replay_line(syslogger: &BasicLogger, prefix: &str, s: &[u8]) {
     }
 }
 
-fn parse_rfc3164_record(line: &str) -> Result<(&str, Level)> {
+fn parse_rfc3164_record(line: &str) -> Result<(&str, Level, Option<(Duration, u32)>)> {
     let mut elements = line.splitn(2, ": ");
     let header = elements.next().unwrap();
     let contents = elements.next().ok_or_else(|| {
@@ -466,7 +638,34 @@ fn parse_rfc3164_record(line: &str) -> Result<(&str, Level)> {
         }
     };
 
-    Ok((contents, level))
+    let (origin, contents) = parse_elapsed_offset(contents);
+
+    Ok((contents, level, origin))
+}
+
+/// Splits the optional `"<secs>.<millis> <pid> "` elapsed-time field that [`format_kmsg_record`]
+/// prepends to records logged with `record.file()` set off the front of `contents`.
+///
+/// Returns `(Some((elapsed, pid)), remainder)` when the field is present and well-formed, or
+/// `(None, contents)` unchanged otherwise -- e.g. for lines logged without `record.file()`, like
+/// the announce lines in `replay_log_file`, which carry no such field.
+fn parse_elapsed_offset(contents: &str) -> (Option<(Duration, u32)>, &str) {
+    let mut parts = contents.splitn(3, ' ');
+    let (Some(secs_millis), Some(pid), Some(rest)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return (None, contents);
+    };
+
+    let Some((secs, millis)) = secs_millis.split_once('.') else {
+        return (None, contents);
+    };
+
+    match (secs.parse(), millis.parse::<u32>(), pid.parse()) {
+        (Ok(secs), Ok(millis), Ok(pid)) => {
+            (Some((Duration::new(secs, millis * 1_000_000), pid)), rest)
+        }
+        _ => (None, contents),
+    }
 }
 
 fn level_from_u8(value: u8) -> Level {
@@ -493,16 +692,127 @@ fn priority_from_level(level: Level) -> usize {
     }
 }
 
-fn create_syslogger() -> BasicLogger {
-    let formatter = Formatter3164 {
-        facility: Facility::LOG_USER,
-        hostname: None,
-        process: "hiberman".into(),
-        pid: std::process::id(),
-    };
+/// Connects to the local syslog daemon. Returns `Err` if none is reachable.
+fn create_syslogger() -> Result<PosixSyslog> {
+    PosixSyslog::connect()
+}
 
-    let logger = syslog::unix(formatter).expect("Could not connect to syslog");
-    BasicLogger::new(logger)
+/// Opens the kernel message buffer device for reading and writing.
+fn open_kmsg() -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(KMSG_PATH)
+        .context("Failed to open kernel message logger")
+}
+
+/// Formats `record` the same way a line destined for kmsg is formatted when logged directly
+/// (`<priority>hiberman: <elapsed> <pid> [<file>:<line>] <message>`), writing into `buf` and
+/// returning the number of bytes written.
+fn format_kmsg_record(buf: &mut [u8], pid: u32, start: Instant, record: &Record) -> Result<usize> {
+    let mut buf_cursor = Cursor::new(buf);
+    let facprio = priority_from_level(record.level()) + (Facility::LOG_USER as usize);
+    if let Some(file) = record.file() {
+        let duration = start.elapsed();
+        write!(
+            &mut buf_cursor,
+            "<{}>{}: {}.{:03} {} [{}:{}] ",
+            facprio,
+            LOG_PREFIX,
+            duration.as_secs(),
+            duration.subsec_millis(),
+            pid,
+            file,
+            record.line().unwrap_or(0)
+        )
+    } else {
+        write!(&mut buf_cursor, "<{}>{}: ", facprio, LOG_PREFIX)
+    }
+    .and_then(|()| writeln!(&mut buf_cursor, "{}", record.args()))
+    .map(|()| buf_cursor.position() as usize)
+    .map_err(|e| anyhow!(e))
+}
+
+/// Sends `record` to `syslogger` if one is connected, otherwise formats it through the same
+/// encoder used for kmsg and writes it directly to `kmsg` so the line isn't lost.
+fn log_record_to(syslogger: &Option<PosixSyslog>, kmsg: &mut File, pid: u32, record: &Record) {
+    match syslogger {
+        Some(syslogger) => syslogger.log(record),
+        None => {
+            let mut buf = [0u8; 1024];
+            if let Ok(len) = format_kmsg_record(&mut buf, pid, Instant::now(), record) {
+                let _ = kmsg.write_all(&buf[..len]);
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// Reusable scratch buffer for formatting a record's message before handing it to
+    /// `libc::syslog`, to avoid an allocation on every log line.
+    static SYSLOG_MESSAGE_BUF: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// A connection to the local syslog daemon via the POSIX `openlog(3)`/`syslog(3)`/`closelog(3)`
+/// interface, used instead of speaking the `/dev/log` datagram protocol directly so libc handles
+/// reconnection, message truncation, and facility/priority encoding.
+struct PosixSyslog {
+    /// `openlog(3)` only stores a pointer to the ident string; keep it alive for as long as this
+    /// connection is open.
+    _ident: CString,
+}
+
+impl PosixSyslog {
+    /// Connects to the local syslog daemon, returning `Err` if none is listening.
+    ///
+    /// `libc::syslog` doesn't report failure if nothing is listening on [`SYSLOG_SOCKET_PATH`]
+    /// (it's a connectionless datagram socket), so without this check a missing daemon would
+    /// silently drop every log line instead of falling back to kmsg.
+    fn connect() -> Result<Self> {
+        UnixDatagram::unbound()
+            .and_then(|sock| sock.connect(SYSLOG_SOCKET_PATH))
+            .with_context(|| format!("No syslog daemon listening on {}", SYSLOG_SOCKET_PATH))?;
+
+        let ident = CStr::from_bytes_with_nul(LOG_IDENT).unwrap().to_owned();
+        // Safety: `ident` is kept alive in `_ident` for the lifetime of this connection, as
+        // required by openlog(3).
+        unsafe {
+            libc::openlog(ident.as_ptr(), libc::LOG_PID, libc::LOG_USER);
+        }
+
+        Ok(PosixSyslog { _ident: ident })
+    }
+
+    /// Formats and forwards `record` to the syslog daemon via `syslog(3)`.
+    fn log(&self, record: &Record) {
+        let priority = priority_from_level(record.level()) as libc::c_int;
+        SYSLOG_MESSAGE_BUF.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            buf.clear();
+            let _ = write!(&mut *buf, "{}", record.args());
+            if let Ok(message) = CString::new(buf.as_slice()) {
+                // Safety: the message is passed as data via "%s", never interpreted as a format
+                // string.
+                unsafe {
+                    libc::syslog(
+                        priority,
+                        b"%s\0".as_ptr() as *const libc::c_char,
+                        message.as_ptr(),
+                    );
+                }
+            }
+        });
+    }
+}
+
+impl Drop for PosixSyslog {
+    fn drop(&mut self) {
+        // Safety: closelog(3) just releases libc's process-wide syslog state; safe to call
+        // unconditionally.
+        unsafe {
+            libc::closelog();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -513,7 +823,16 @@ mod test {
     fn test_parse_rfc3164_record_good() {
         let l = "<11>hiberman: R [src/hiberman.rs:529] Hello 2004";
         let rec = parse_rfc3164_record(l).unwrap();
-        assert_eq!(rec, ("R [src/hiberman.rs:529] Hello 2004", Level::Error));
+        assert_eq!(rec, ("R [src/hiberman.rs:529] Hello 2004", Level::Error, None));
+    }
+
+    #[test]
+    fn test_parse_rfc3164_record_with_elapsed_offset() {
+        let l = "<11>hiberman: 12.045 1234 [src/hiberman.rs:529] Hello 2004";
+        let (contents, level, origin) = parse_rfc3164_record(l).unwrap();
+        assert_eq!(contents, "[src/hiberman.rs:529] Hello 2004");
+        assert_eq!(level, Level::Error);
+        assert_eq!(origin, Some((Duration::new(12, 45_000_000), 1234)));
     }
 
     #[test]
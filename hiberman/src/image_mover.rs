@@ -0,0 +1,233 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A pipelined, multi-threaded replacement for a single-threaded copy of the
+//! hiberimage into the snapshot device.
+//!
+//! [`ImageMover`] runs a pool of reader workers, each issuing `BUFFER_PAGES`-
+//! sized reads against disjoint, striped regions of the HIBERIMAGE device
+//! into preallocated, page-aligned mmap buffers drawn from a shared pool, and
+//! hands completed buffers to a single writer over a bounded channel. The
+//! writer reassembles them in order (workers can finish out of order) and
+//! feeds them to the snapshot device, recycling each buffer back to the pool
+//! once written. This keeps the drive saturated with outstanding reads
+//! instead of leaving it idle between each synchronous read-then-write step.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use memmap2::MmapMut;
+
+/// Number of pages read into each buffer.
+const BUFFER_PAGES: usize = 32;
+const PAGE_SIZE: usize = 4096;
+const CHUNK_SIZE: usize = BUFFER_PAGES * PAGE_SIZE;
+
+/// Default number of concurrent reader workers.
+pub const DEFAULT_WORKER_COUNT: usize = 4;
+/// Default number of in-flight buffers kept per worker, i.e. how many chunks
+/// ahead of the writer each worker is allowed to get.
+pub const DEFAULT_BUFFERS_PER_WORKER: usize = 2;
+
+/// A completed read: the chunk's sequence number within the device, its
+/// buffer, and how many bytes of it are valid (the final chunk is typically
+/// short).
+struct FilledChunk {
+    sequence: u64,
+    buf: MmapMut,
+    len: usize,
+}
+
+/// Reads a device through a pool of reader workers and writes it to a sink in
+/// order, overlapping reads with each other to keep the drive's queue depth
+/// filled. See the module documentation for the full design.
+pub struct ImageMover {
+    worker_count: usize,
+    buffers_per_worker: usize,
+}
+
+impl ImageMover {
+    /// Creates a mover with `worker_count` reader threads, each allowed
+    /// `buffers_per_worker` outstanding chunks.
+    pub fn new(worker_count: usize, buffers_per_worker: usize) -> Self {
+        ImageMover {
+            worker_count: worker_count.max(1),
+            buffers_per_worker: buffers_per_worker.max(1),
+        }
+    }
+
+    /// Moves all of `source` into `sink`, returning the total bytes moved.
+    pub fn run(&self, source: File, mut sink: impl Write) -> Result<u64> {
+        let file_len = source
+            .metadata()
+            .context("Failed to stat image source")?
+            .len();
+        if file_len == 0 {
+            return Ok(0);
+        }
+
+        let total_chunks = (file_len + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
+        let depth = self.worker_count * self.buffers_per_worker;
+
+        let (filled_tx, filled_rx) = crossbeam_channel::bounded::<Result<FilledChunk>>(depth);
+        let (free_tx, free_rx) = crossbeam_channel::bounded::<MmapMut>(depth);
+        for _ in 0..depth {
+            free_tx
+                .send(MmapMut::map_anon(CHUNK_SIZE).context("Failed to allocate mover buffer")?)
+                .expect("free channel was just created");
+        }
+
+        let next_sequence = Arc::new(AtomicU64::new(0));
+        let workers = (0..self.worker_count)
+            .map(|_| {
+                let mut file = source
+                    .try_clone()
+                    .context("Failed to duplicate image source fd for reader worker")?;
+                let free_rx = free_rx.clone();
+                let filled_tx = filled_tx.clone();
+                let next_sequence = next_sequence.clone();
+
+                Ok::<JoinHandle<()>, anyhow::Error>(thread::spawn(move || loop {
+                    let sequence = next_sequence.fetch_add(1, Ordering::SeqCst);
+                    if sequence >= total_chunks {
+                        break;
+                    }
+
+                    let mut buf = match free_rx.recv() {
+                        Ok(buf) => buf,
+                        Err(_) => break,
+                    };
+
+                    let result = file
+                        .seek(SeekFrom::Start(sequence * CHUNK_SIZE as u64))
+                        .context("Failed to seek reader worker")
+                        .and_then(|_| read_full_chunk(&mut file, &mut buf));
+
+                    let sent = match result {
+                        Ok(len) => filled_tx.send(Ok(FilledChunk { sequence, buf, len })),
+                        Err(err) => filled_tx.send(Err(err)),
+                    };
+                    if sent.is_err() {
+                        break;
+                    }
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        drop(filled_tx);
+        drop(free_rx);
+
+        // Single writer: reassembles chunks in order, since workers racing
+        // over disjoint regions can finish out of order.
+        let mut pending = HashMap::new();
+        let mut next_to_write = 0u64;
+        let mut bytes_moved = 0u64;
+        let mut write_error = None;
+        let mut read_error = None;
+
+        for received in &filled_rx {
+            let chunk = match received {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    read_error = Some(err);
+                    break;
+                }
+            };
+            pending.insert(chunk.sequence, chunk);
+
+            while let Some(chunk) = pending.remove(&next_to_write) {
+                if write_error.is_none() {
+                    if let Err(err) = sink
+                        .write_all(&chunk.buf[..chunk.len])
+                        .context("Failed to write chunk to snapshot device")
+                    {
+                        write_error = Some(err);
+                    } else {
+                        bytes_moved += chunk.len as u64;
+                    }
+                }
+                next_to_write += 1;
+                // Recycle the buffer regardless of write outcome, so readers
+                // already blocked on free_rx can exit cleanly once they next
+                // check next_sequence against total_chunks.
+                let _ = free_tx.send(chunk.buf);
+            }
+        }
+
+        if read_error.is_some() {
+            // The loop above broke early, leaving reassembled-but-unwritten
+            // buffers in `pending` and further in-flight results still
+            // queued in `filled_rx` (or yet to be sent by workers still
+            // running). Neither gets recycled by the normal path above, and
+            // `free_tx` isn't closed until `workers.join()` below returns --
+            // so without this, a worker already blocked on `free_rx.recv()`
+            // (or about to block once it finishes its current read) would
+            // wait forever for a buffer that's never coming, hanging resume
+            // indefinitely. Recycle everything still outstanding so every
+            // worker can keep making progress until it runs out of chunks
+            // and exits on its own.
+            for (_, chunk) in pending.drain() {
+                let _ = free_tx.send(chunk.buf);
+            }
+            for received in &filled_rx {
+                if let Ok(chunk) = received {
+                    let _ = free_tx.send(chunk.buf);
+                }
+            }
+        }
+
+        // Always join the reader workers before returning, even on an early
+        // read error, so none are left running against the channels/file
+        // this function is about to drop. `filled_rx` is dropped here (it
+        // was only ever borrowed above), so a worker blocked on
+        // `filled_tx.send` will see it disconnected and exit on its own.
+        drop(filled_rx);
+        for worker in workers {
+            let _ = worker.join();
+        }
+
+        if let Some(err) = read_error {
+            return Err(err);
+        }
+        if let Some(err) = write_error {
+            return Err(err);
+        }
+        if next_to_write != total_chunks {
+            bail!(
+                "Image mover finished short: wrote {} of {} chunks",
+                next_to_write,
+                total_chunks
+            );
+        }
+
+        Ok(bytes_moved)
+    }
+}
+
+/// Reads into `buf` until it's full or the source is exhausted, returning the
+/// number of bytes read.
+fn read_full_chunk(file: &mut File, buf: &mut MmapMut) -> Result<usize> {
+    let mut total_read = 0;
+    while total_read < buf.len() {
+        match file
+            .read(&mut buf[total_read..])
+            .context("Failed to read image chunk")?
+        {
+            0 => break,
+            n => total_read += n,
+        }
+    }
+    Ok(total_read)
+}
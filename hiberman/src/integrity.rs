@@ -0,0 +1,88 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! HMAC-SHA256 based integrity verification for the hibernate image.
+//!
+//! During suspend, [`compute_tag`] streams an HMAC-SHA256 over the image
+//! bytes keyed by the TPM-derived integrity key and the tag is stored in the
+//! hibermeta LV via [`write_tag`]. During resume, [`verify_tag`] recomputes
+//! the HMAC over a streaming pass of the HIBERIMAGE device and compares it
+//! against the stored tag before the restore is allowed to proceed, so a
+//! corrupted or tampered image is rejected rather than restored blindly.
+//! Keying the check lets it also double as tamper detection, using the same
+//! secret already loaded for this resume.
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use hmac::Hmac;
+use hmac::Mac;
+use sha2::Sha256;
+
+/// Name of the tag file stored in the hibermeta LV.
+pub const INTEGRITY_TAG_FILE_NAME: &str = "image_integrity_tag";
+
+/// Size in bytes of the stored HMAC-SHA256 tag.
+const TAG_SIZE: usize = 32;
+
+/// Size of the chunks streamed through the HMAC while reading the image.
+const STREAM_CHUNK_SIZE: usize = 1 << 16;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Streams an HMAC-SHA256 over all bytes produced by `reader`, keyed by
+/// `key`.
+fn stream_hmac(key: &[u8], mut reader: impl Read) -> Result<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(key).context("Failed to create image HMAC")?;
+    let mut buf = [0u8; STREAM_CHUNK_SIZE];
+
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .context("Failed to read image for HMAC")?;
+        if n == 0 {
+            break;
+        }
+        mac.update(&buf[..n]);
+    }
+
+    Ok(mac)
+}
+
+/// Computes the HMAC-SHA256 tag over `reader`'s bytes, keyed by `key`. Called
+/// during suspend, over the image as it is written to the HIBERIMAGE device.
+pub fn compute_tag(key: &[u8], reader: impl Read) -> Result<[u8; TAG_SIZE]> {
+    let mut tag = [0u8; TAG_SIZE];
+    tag.copy_from_slice(&stream_hmac(key, reader)?.finalize().into_bytes());
+    Ok(tag)
+}
+
+/// Writes `tag` to `path`, for later verification during resume.
+pub fn write_tag(path: &Path, tag: &[u8; TAG_SIZE]) -> Result<()> {
+    File::create(path)
+        .and_then(|mut f| f.write_all(tag))
+        .with_context(|| format!("Failed to write integrity tag to {}", path.display()))
+}
+
+/// Recomputes the HMAC-SHA256 over `reader`'s bytes, keyed by `key`, and
+/// compares it against the tag stored at `tag_path`. Bails with a descriptive
+/// error on a read failure, a missing/malformed tag file, or a mismatch.
+pub fn verify_tag(key: &[u8], reader: impl Read, tag_path: &Path) -> Result<()> {
+    let mut expected = [0u8; TAG_SIZE];
+    File::open(tag_path)
+        .and_then(|mut f| f.read_exact(&mut expected))
+        .with_context(|| format!("Failed to read integrity tag from {}", tag_path.display()))?;
+
+    let mac = stream_hmac(key, reader)?;
+    if mac.verify_slice(&expected).is_err() {
+        bail!("Hiberimage integrity check failed: HMAC does not match stored tag");
+    }
+
+    Ok(())
+}
@@ -9,10 +9,13 @@ use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::mem;
+use std::path::Path;
+use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 use std::time::UNIX_EPOCH;
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use libchromeos::secure_blob::SecureBlob;
@@ -38,14 +41,21 @@ use crate::hiberutil::HibernateError;
 use crate::hiberutil::HibernateStage;
 use crate::hiberutil::ResumeOptions;
 use crate::hiberutil::TimestampFile;
+use crate::image_mover::ImageMover;
+use crate::image_mover::DEFAULT_BUFFERS_PER_WORKER;
+use crate::image_mover::DEFAULT_WORKER_COUNT;
+use crate::integrity;
 use crate::lvm::activate_physical_lv;
 use crate::metrics::read_and_send_metrics;
 use crate::metrics::METRICS_LOGGER;
 use crate::powerd::PowerdPendingResume;
+use crate::preloader::ImagePreloader;
 use crate::resume_dbus::{get_user_key, wait_for_resume_dbus_event, ResumeRequest};
 use crate::snapdev::FrozenUserspaceTicket;
 use crate::snapdev::SnapshotDevice;
 use crate::snapdev::SnapshotMode;
+use crate::snapshot_monitor::DmSnapshotSpaceMonitor;
+use crate::snapshot_monitor::DEFAULT_HIGH_WATER_PCT;
 use crate::volume::ActiveMount;
 use crate::volume::PendingStatefulMerge;
 use crate::volume::VolumeManager;
@@ -54,6 +64,12 @@ use crate::volume::VolumeManager;
 const TPM_SEED_SIZE: usize = 32;
 /// The path where the TPM key will be stored.
 const TPM_SEED_FILE: &str = "/run/hiberman/tpm_seed";
+/// The `dmsetup`-recognized name of the stateful dm-snapshot device watched by
+/// the `DmSnapshotSpaceMonitor` over the pending-merge window.
+const STATEFUL_SNAPSHOT_DM_NAME: &str = "stateful-rw";
+/// Default bound on how long to wait for cryptohome/Chrome to deliver the
+/// resume user key over D-Bus, used by `ResumeOptions::resume_dbus_timeout`.
+pub const DEFAULT_RESUME_DBUS_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// The ResumeConductor orchestrates the various individual instruments that
 /// work in concert to resume the system from hibernation.
@@ -61,6 +77,10 @@ pub struct ResumeConductor {
     options: ResumeOptions,
     stateful_block_path: String,
     timestamp_start: Duration,
+    /// The TPM-derived key fetched in `setup_snapshot_device()`, kept around
+    /// so the later integrity check can key its HMAC with the same secret
+    /// without re-reading (and re-unlinking) the TPM seed file.
+    tpm_key: Option<SecureBlob>,
 }
 
 impl ResumeConductor {
@@ -70,6 +90,7 @@ impl ResumeConductor {
             options: Default::default(),
             stateful_block_path: path_to_stateful_block()?,
             timestamp_start: Duration::ZERO,
+            tpm_key: None,
         })
     }
 
@@ -85,6 +106,14 @@ impl ResumeConductor {
         // function returns one way or another.
         let mut volume_manager = VolumeManager::new()?;
         let pending_merge = PendingStatefulMerge::new(&mut volume_manager)?;
+        // Watch the stateful dm-snapshot's COW space for the same window the
+        // merge is pending over; an emergency reboot is better than letting
+        // the snapshot silently go invalid if it fills up first.
+        let snapshot_space_monitor = DmSnapshotSpaceMonitor::start(
+            STATEFUL_SNAPSHOT_DM_NAME.to_string(),
+            self.stateful_block_path.clone(),
+            DEFAULT_HIGH_WATER_PCT,
+        );
         // Start keeping logs in memory, anticipating success.
         redirect_log(HiberlogOut::BufferInMemory);
 
@@ -106,8 +135,10 @@ impl ResumeConductor {
         remove_resume_in_progress_file();
         // Since resume_inner() returned, we are no longer in a viable resume
         // path. Drop the pending merge object, causing the stateful
-        // dm-snapshots to merge with their origins.
+        // dm-snapshots to merge with their origins, and stop watching its
+        // COW space now that the merge is no longer pending.
         drop(pending_merge);
+        drop(snapshot_space_monitor);
         // Read the metrics files to send out samples.
         read_and_send_metrics();
 
@@ -154,10 +185,24 @@ impl ResumeConductor {
             .open(DeviceMapper::device_path(VolumeManager::HIBERIMAGE).unwrap())
             .unwrap();
 
+        // Start warming the page cache for the image well ahead of the
+        // synchronous load below. `--no-preload` keeps the old single-pass
+        // path available for debugging.
+        let preloader = if self.options.no_preload {
+            None
+        } else {
+            OpenOptions::new()
+                .read(true)
+                .create(false)
+                .open(DeviceMapper::device_path(VolumeManager::HIBERIMAGE).unwrap())
+                .ok()
+                .map(ImagePreloader::start_default)
+        };
+
         volume_manager.lockdown_hiberimage()?;
 
         let _locked_memory = lock_process_memory()?;
-        self.resume_system(hiber_image_file, hibermeta_mount)
+        self.resume_system(hiber_image_file, preloader, hibermeta_mount)
     }
 
     /// Helper function to evaluate the hibernate cookie and decide whether or
@@ -207,6 +252,7 @@ impl ResumeConductor {
     fn resume_system(
         &mut self,
         hiber_image_file: File,
+        preloader: Option<ImagePreloader>,
         mut hibermeta_mount: ActiveMount,
     ) -> Result<()> {
         let log_file_path = hiberlog::LogFile::get_path(HibernateStage::Resume);
@@ -217,14 +263,42 @@ impl ResumeConductor {
         let mut snap_dev = SnapshotDevice::new(SnapshotMode::Write)?;
 
         let start = Instant::now();
-        // Load the snapshot image into the kernel
-        let image_size = snap_dev.load_image(hiber_image_file)?;
+        // Load the snapshot image into the kernel. A pool of reader workers
+        // keeps several chunks of the device in flight at once instead of
+        // leaving the drive idle between each synchronous read-then-write
+        // step, which matters most on high-bandwidth storage like NVMe.
+        let image_size = ImageMover::new(DEFAULT_WORKER_COUNT, DEFAULT_BUFFERS_PER_WORKER)
+            .run(hiber_image_file, &mut snap_dev)?;
+
+        // The preloader's read-ahead is done its job by now; drain and join
+        // it so its "PreloadImage" sample lands before the image-load sample.
+        if let Some(preloader) = preloader {
+            preloader.finish();
+        }
 
         {
             let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
             metrics_logger.metrics_send_io_sample("ReadMainImage", image_size, start.elapsed());
         }
 
+        // Verify the image wasn't corrupted or tampered with before trusting
+        // it enough to freeze userspace and restore it.
+        let tag_path = hibermeta_mount
+            .mountpoint()
+            .join(integrity::INTEGRITY_TAG_FILE_NAME);
+        if let Err(err) = self.verify_image_integrity(&tag_path, image_size) {
+            error!("Hiberimage integrity check failed: {:#}", err);
+            set_hibernate_cookie(
+                Some(&self.stateful_block_path),
+                HibernateCookieValue::NoResume,
+            )
+            .context("Failed to set hibernate cookie to NoResume after integrity failure")?;
+            let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
+            metrics_logger.metrics_send_failure_sample("ImageIntegrityCheckFailed");
+
+            return Err(err);
+        }
+
         // Let other daemons know it's the end of the world.
         let _powerd_resume =
             PowerdPendingResume::new().context("Failed to call powerd for imminent resume")?;
@@ -291,16 +365,33 @@ impl ResumeConductor {
     ) -> Result<()> {
         // Load the TPM derived key.
         let tpm_key: SecureBlob = self.get_tpm_derived_integrity_key()?;
+        // Keep it around for the image integrity check later in resume_system().
+        self.tpm_key = Some(tpm_key.clone());
+
+        let dbus_event = wait_for_resume_dbus_event_with_timeout(
+            completion_receiver,
+            self.options.resume_dbus_timeout,
+        )?;
 
-        let user_key = match wait_for_resume_dbus_event(completion_receiver)? {
+        let user_key = match dbus_event {
             ResumeRequest::ResumeAccountId { account_id } => get_user_key(&account_id, &[])?,
             ResumeRequest::ResumeAuthSessionId { auth_session_id } => {
                 get_user_key("", &auth_session_id)?
             }
             ResumeRequest::Abort { reason } => {
-                // Abort resume.
-                info!("Aborting resume: {:?}", reason);
-                return Ok(());
+                // Abort resume, whether requested explicitly or synthesized
+                // by a wait timeout: a missing key should be a recoverable
+                // fresh boot rather than a wedged device.
+                warn!("Aborting resume: {:?}", reason);
+                {
+                    let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
+                    metrics_logger.metrics_send_failure_sample("ResumeDbusUserKeyAborted");
+                }
+                VolumeManager::new()?
+                    .teardown_hiberimage()
+                    .context("Failed to tear down hiberimage after resume abort")?;
+
+                bail!("Resume aborted: {:?}", reason);
             }
         };
 
@@ -331,6 +422,31 @@ impl ResumeConductor {
         Ok(SecureBlob::from(buf))
     }
 
+    /// Recomputes the HMAC-SHA256 over a fresh streaming pass of the first
+    /// `image_size` bytes of the HIBERIMAGE device, keyed by the TPM-derived
+    /// key fetched in `setup_snapshot_device()`, and compares it against the
+    /// tag stored at `tag_path`. Bounding the pass to `image_size` matters
+    /// because the device itself can be larger than the image written to it
+    /// (e.g. thin-provisioned), and the suspend side's tag never covered that
+    /// trailing slack.
+    fn verify_image_integrity(&self, tag_path: &Path, image_size: u64) -> Result<()> {
+        let tpm_key = self
+            .tpm_key
+            .as_ref()
+            .context("No TPM-derived integrity key available for image verification")?;
+
+        let device_path = DeviceMapper::device_path(VolumeManager::HIBERIMAGE)
+            .context("No hiberimage device path for image verification")?;
+        let image_file = File::open(&device_path).with_context(|| {
+            format!(
+                "Failed to open {} for image verification",
+                device_path.display()
+            )
+        })?;
+
+        integrity::verify_tag(tpm_key.as_ref(), image_file.take(image_size), tag_path)
+    }
+
     /// Jump into the already-loaded resume image. The PendingResumeCall isn't
     /// actually used, but is received to enforce the lifetime of the object.
     /// This prevents bugs where it accidentally gets dropped by the caller too
@@ -346,3 +462,30 @@ impl ResumeConductor {
         result
     }
 }
+
+/// Waits for `wait_for_resume_dbus_event()` to complete, bounded by `timeout`.
+/// On timeout, synthesizes a `ResumeRequest::Abort` rather than blocking
+/// forever on a user-key exchange that may never arrive (a crashed UI or a
+/// stuck session manager).
+fn wait_for_resume_dbus_event_with_timeout(
+    completion_receiver: crossbeam_channel::Receiver<()>,
+    timeout: Duration,
+) -> Result<ResumeRequest> {
+    let (result_tx, result_rx) = crossbeam_channel::bounded(1);
+    thread::spawn(move || {
+        let _ = result_tx.send(wait_for_resume_dbus_event(completion_receiver));
+    });
+
+    match result_rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(crossbeam_channel::RecvTimeoutError::Timeout) => Ok(ResumeRequest::Abort {
+            reason: format!(
+                "Timed out after {:?} waiting for the resume D-Bus user-key exchange",
+                timeout
+            ),
+        }),
+        Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+            bail!("Resume D-Bus event channel disconnected while waiting for the user key")
+        }
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Watches the stateful dm-snapshot's copy-on-write space while the resume
+//! window is open.
+//!
+//! `ResumeConductor::resume()` holds a `PendingStatefulMerge` for as long as
+//! the bootstrap system keeps running after a successful resume. If the
+//! stateful dm-snapshot's COW space fills before that merge completes, the
+//! device silently goes invalid, risking filesystem corruption.
+//! [`DmSnapshotSpaceMonitor`] polls `dmsetup status` on the snapshot device
+//! for that window and forces an emergency reboot if utilization crosses a
+//! high-water mark, rather than letting the snapshot run out from under the
+//! running system.
+
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::error;
+use log::warn;
+
+use crate::cookie::set_hibernate_cookie;
+use crate::cookie::HibernateCookieValue;
+use crate::metrics::METRICS_LOGGER;
+
+/// How often to poll `dmsetup status` on the snapshot device.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Default utilization, as a percentage of allocated COW sectors, above which
+/// the snapshot is considered at risk of going invalid.
+pub const DEFAULT_HIGH_WATER_PCT: f64 = 95.0;
+
+/// Polls a dm-snapshot device's COW space utilization on a background thread
+/// and forces an emergency reboot if it crosses a high-water mark, before the
+/// snapshot itself goes invalid.
+///
+/// Create with [`DmSnapshotSpaceMonitor::start`] alongside the
+/// `PendingStatefulMerge` it watches over, and drop (or call
+/// [`DmSnapshotSpaceMonitor::stop`]) it in the same scope the merge is
+/// dropped in, so the monitor only runs for as long as the merge is pending.
+pub struct DmSnapshotSpaceMonitor {
+    stop: Arc<AtomicBool>,
+    /// Peak utilization observed so far, as a percentage scaled by 100 (e.g.
+    /// 9512 for 95.12%), for lock-free reporting.
+    peak_utilization_pct_x100: Arc<AtomicU32>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl DmSnapshotSpaceMonitor {
+    /// Spawns the poll loop for `device_name` (a `dmsetup`-recognized device
+    /// name), forcing an emergency reboot of `stateful_block_path` once
+    /// utilization crosses `high_water_pct`.
+    pub fn start(device_name: String, stateful_block_path: String, high_water_pct: f64) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let peak_utilization_pct_x100 = Arc::new(AtomicU32::new(0));
+
+        let worker_stop = stop.clone();
+        let worker_peak = peak_utilization_pct_x100.clone();
+        let worker = thread::spawn(move || {
+            while !worker_stop.load(Ordering::Relaxed) {
+                match poll_utilization_pct(&device_name) {
+                    Ok(Some(utilization_pct)) => {
+                        worker_peak.fetch_max((utilization_pct * 100.0) as u32, Ordering::Relaxed);
+
+                        if utilization_pct >= high_water_pct {
+                            emergency_reboot(&stateful_block_path, &format!(
+                                "Stateful dm-snapshot COW space at {:.1}%, crossing the {:.1}% high-water mark",
+                                utilization_pct, high_water_pct
+                            ));
+                            break;
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(err) => {
+                        warn!(
+                            "Failed to poll dm-snapshot status for {}: {}",
+                            device_name, err
+                        );
+                    }
+                }
+
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+
+        DmSnapshotSpaceMonitor {
+            stop,
+            peak_utilization_pct_x100,
+            worker: Some(worker),
+        }
+    }
+
+    /// Stops the poll loop, joins the background thread, and emits the peak
+    /// observed utilization as a metrics sample for tuning snapshot sizing. A
+    /// no-op if already stopped.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+
+            let peak_pct = self.peak_utilization_pct_x100.load(Ordering::Relaxed) / 100;
+            let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
+            metrics_logger.metrics_send_percentage_sample("DmSnapshotCowPeakUtilization", peak_pct);
+        }
+    }
+}
+
+impl Drop for DmSnapshotSpaceMonitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Runs `dmsetup status <device_name>` and parses the `used/total` allocated
+/// sector fields out of a dm-snapshot status line, returning the utilization
+/// as a percentage. Returns `Ok(None)` for a status line this doesn't
+/// recognize (e.g. the target isn't a snapshot, or it's already `Invalid`).
+fn poll_utilization_pct(device_name: &str) -> anyhow::Result<Option<f64>> {
+    let output = Command::new("dmsetup")
+        .args(["status", device_name])
+        .output()?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "dmsetup status {} exited with {}",
+            device_name,
+            output.status
+        );
+    }
+
+    let status = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = status.split_whitespace().collect();
+    // A snapshot status line looks like: "<start> <len> snapshot <used>/<total>".
+    if fields.len() < 4 || fields[2] != "snapshot" {
+        return Ok(None);
+    }
+
+    let Some((used, total)) = fields[3].split_once('/') else {
+        return Ok(None);
+    };
+    let (Ok(used), Ok(total)) = (used.parse::<f64>(), total.parse::<f64>()) else {
+        return Ok(None);
+    };
+
+    if total == 0.0 {
+        return Ok(None);
+    }
+
+    Ok(Some(used / total * 100.0))
+}
+
+/// Sets the hibernate cookie to `EmergencyReboot` and reboots the system
+/// immediately, logging `reason` first.
+fn emergency_reboot(stateful_block_path: &str, reason: &str) {
+    error!("Forcing emergency reboot: {}", reason);
+
+    if let Err(err) = set_hibernate_cookie(
+        Some(stateful_block_path),
+        HibernateCookieValue::EmergencyReboot,
+    ) {
+        error!("Failed to set EmergencyReboot cookie: {}", err);
+    }
+
+    // This is safe because sync() does not modify memory.
+    unsafe {
+        libc::sync();
+        libc::reboot(libc::RB_AUTOBOOT);
+    }
+}
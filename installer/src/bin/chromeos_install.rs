@@ -6,32 +6,124 @@
 //! following the deshell playbook here:
 //! https://github.com/google/deshell/blob/main/playbook.md
 
-use std::process::{Command, ExitCode};
+use std::process::{Command, ExitCode, Stdio};
 use nix::unistd;
 
+mod config;
+mod dbus_progress;
+mod hooks;
+mod logging;
+mod mount;
+
+use config::{Config, ConfigError};
+use dbus_progress::InstallProgressReporter;
+use hooks::HookManifest;
+
 fn main() -> ExitCode {
     libchromeos::panic_handler::install_memfd_handler();
 
+    let mut args: Vec<String> = std::env::args().collect();
     // Don't include argv[0], the executable name, when passing args.
-    let args = std::env::args().skip(1);
+    let program = args.remove(0);
+
+    let config = match Config::parse(&program, &args) {
+        Ok(config) => config,
+        Err(ConfigError::HelpRequested) => return ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("chromeos-install: {}", e);
+            config::usage(&program);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    logging::init(config.log_level.as_deref());
 
     // Fail if not running as root.
     if !unistd::Uid::effective().is_root() {
-        eprintln!("chromeos-install must be run as root");
+        log::error!("chromeos-install must be run as root");
         return ExitCode::FAILURE;
     }
 
-    if let Ok(status) = Command::new("/usr/sbin/chromeos-install.sh")
-        .args(args)
-        .status()
-    {
-        if status.success() {
-            ExitCode::SUCCESS
-        } else {
-            ExitCode::FAILURE
+    // Installed before any MountGuard/LoopDevice exists, so that a signal
+    // caught mid-install unwinds through their Drop impls instead of
+    // leaving the target mounted or a loop device attached.
+    if let Err(e) = mount::install_signal_handler() {
+        log::error!("Failed to install signal handler: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let reporter = InstallProgressReporter::new()
+        .map_err(|e| log::warn!("Couldn't connect to D-Bus, progress signals disabled: {}", e))
+        .ok();
+
+    let child = Command::new("/usr/sbin/chromeos-install.sh")
+        .args(config.to_argv())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => {
+            log::error!("Couldn't launch chromeos-install.sh");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    dbus_progress::forward_progress(&mut child, reporter.as_ref());
+
+    let result = match child.wait() {
+        Ok(status) => status.success(),
+        Err(_) => false,
+    };
+
+    if let Some(reporter) = &reporter {
+        let error = if result { "" } else { "chromeos-install.sh failed" };
+        reporter.emit_complete(result, error);
+        dbus_progress::wait_for_signal_flush();
+    }
+
+    if result {
+        if let Err(e) = run_post_install_hooks(&config) {
+            log::error!("Post-install customization failed: {}", e);
+            return ExitCode::FAILURE;
         }
+        ExitCode::SUCCESS
     } else {
-        eprintln!("Couldn't launch chromeos-install.sh");
         ExitCode::FAILURE
     }
 }
+
+/// Mounts the newly installed rootfs and applies the post-install
+/// customization manifest, if one was given. The rootfs is always
+/// unmounted afterward, whether or not hook application succeeds.
+fn run_post_install_hooks(config: &Config) -> std::io::Result<()> {
+    let manifest_path = match &config.post_install_manifest {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let dst = config.dst.as_deref().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--post_install_manifest requires --dst",
+        )
+    })?;
+
+    let manifest = HookManifest::load(manifest_path)?;
+    let rootfs_partition = mount::rootfs_partition(dst);
+    let mount = mount::MountGuard::mount_scratch(&rootfs_partition, "ext2")?;
+
+    if mount::interrupted() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            "interrupted by a signal before applying customizations",
+        ));
+    }
+
+    hooks::apply(
+        &manifest,
+        &mount,
+        config.channel.as_deref().unwrap_or(""),
+        config.variant.as_deref().unwrap_or(""),
+    )
+}
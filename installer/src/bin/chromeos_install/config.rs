@@ -0,0 +1,242 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Command line parsing and validation for the chromeos-install wrapper.
+//!
+//! This mirrors the flag surface that `chromeos-install.sh` accepts, but
+//! validates it up front so a bad invocation fails fast instead of deep
+//! inside the shell script.
+
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+
+use getopts::Options;
+
+/// Parsed and validated arguments for `chromeos-install.sh`.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    pub dst: Option<PathBuf>,
+    pub payload_image: Option<PathBuf>,
+    pub payload_dir: Option<PathBuf>,
+    pub skip_postinstall: bool,
+    pub skip_rootfs: bool,
+    pub yes: bool,
+    pub lab_preserve_logs: bool,
+    pub pmbr_code: Option<String>,
+    pub target_bios: Option<String>,
+    pub log_level: Option<String>,
+    pub post_install_manifest: Option<PathBuf>,
+    pub channel: Option<String>,
+    pub variant: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was requested; `usage()` has already been printed.
+    HelpRequested,
+    Parse(getopts::Fail),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::HelpRequested => write!(f, "help requested"),
+            ConfigError::Parse(e) => write!(f, "{}", e),
+            ConfigError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn build_options() -> Options {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help message and exit.");
+    opts.optopt("", "dst", "Destination block device to install to.", "DST");
+    opts.optopt(
+        "",
+        "payload_image",
+        "Path to a specific rootfs payload image to install.",
+        "IMAGE",
+    );
+    opts.optopt(
+        "",
+        "payload_dir",
+        "Directory containing rootfs/kernel payloads.",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "skip_postinstall",
+        "Skip running postinstall on the new rootfs.",
+    );
+    opts.optflag("", "skip_rootfs", "Skip writing the rootfs partitions.");
+    opts.optflag("y", "yes", "Don't ask any questions, just do the install.");
+    opts.optflag(
+        "",
+        "lab_preserve_logs",
+        "Preserve existing logs on the target device (lab machines only).",
+    );
+    opts.optopt(
+        "",
+        "pmbr_code",
+        "Path to the PMBR code to install into the new disk's MBR.",
+        "CODE",
+    );
+    opts.optopt(
+        "",
+        "target_bios",
+        "BIOS type to install for: u-boot, legacy, efi, secure.",
+        "BIOS",
+    );
+    opts.optopt(
+        "",
+        "log-level",
+        "Minimum severity to log (error, warn, info, debug, trace). \
+         Defaults to $RUST_LOG, or info.",
+        "LEVEL",
+    );
+    opts.optopt(
+        "",
+        "post_install_manifest",
+        "Path to a TOML manifest of post-install customizations to apply \
+         to the new rootfs.",
+        "MANIFEST",
+    );
+    opts.optopt(
+        "",
+        "channel",
+        "Image channel, used to gate post-install customizations.",
+        "CHANNEL",
+    );
+    opts.optopt(
+        "",
+        "variant",
+        "Image variant, used to gate post-install customizations.",
+        "VARIANT",
+    );
+    opts
+}
+
+/// Prints usage information to stderr.
+pub fn usage(program: &str) {
+    let opts = build_options();
+    eprint!(
+        "{}",
+        opts.usage(&format!("Usage: {} [options]", program))
+    );
+}
+
+impl Config {
+    /// Parses and validates `args` (not including argv[0]).
+    pub fn parse(program: &str, args: &[String]) -> Result<Config, ConfigError> {
+        let opts = build_options();
+        let matches = opts.parse(args).map_err(ConfigError::Parse)?;
+
+        if matches.opt_present("help") {
+            usage(program);
+            return Err(ConfigError::HelpRequested);
+        }
+
+        let config = Config {
+            dst: matches.opt_str("dst").map(PathBuf::from),
+            payload_image: matches.opt_str("payload_image").map(PathBuf::from),
+            payload_dir: matches.opt_str("payload_dir").map(PathBuf::from),
+            skip_postinstall: matches.opt_present("skip_postinstall"),
+            skip_rootfs: matches.opt_present("skip_rootfs"),
+            yes: matches.opt_present("yes"),
+            lab_preserve_logs: matches.opt_present("lab_preserve_logs"),
+            pmbr_code: matches.opt_str("pmbr_code"),
+            target_bios: matches.opt_str("target_bios"),
+            log_level: matches.opt_str("log-level"),
+            post_install_manifest: matches.opt_str("post_install_manifest").map(PathBuf::from),
+            channel: matches.opt_str("channel"),
+            variant: matches.opt_str("variant"),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(dst) = &self.dst {
+            let metadata = fs::metadata(dst).map_err(|e| {
+                ConfigError::Invalid(format!("--dst {} does not exist: {}", dst.display(), e))
+            })?;
+            if !metadata.file_type().is_block_device() {
+                return Err(ConfigError::Invalid(format!(
+                    "--dst {} is not a block device",
+                    dst.display()
+                )));
+            }
+        }
+
+        if self.payload_image.is_some() && self.payload_dir.is_some() {
+            return Err(ConfigError::Invalid(
+                "--payload_image and --payload_dir are mutually exclusive".to_string(),
+            ));
+        }
+
+        if self.skip_rootfs && self.payload_image.is_some() {
+            return Err(ConfigError::Invalid(
+                "--skip_rootfs and --payload_image are mutually exclusive".to_string(),
+            ));
+        }
+
+        if let Some(bios) = &self.target_bios {
+            const VALID_BIOS: &[&str] = &["u-boot", "legacy", "efi", "secure"];
+            if !VALID_BIOS.contains(&bios.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "--target_bios must be one of {:?}, got {:?}",
+                    VALID_BIOS, bios
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reconstructs a normalized argv to pass on to `chromeos-install.sh`.
+    pub fn to_argv(&self) -> Vec<String> {
+        let mut argv = Vec::new();
+
+        if let Some(dst) = &self.dst {
+            argv.push("--dst".to_string());
+            argv.push(dst.display().to_string());
+        }
+        if let Some(payload_image) = &self.payload_image {
+            argv.push("--payload_image".to_string());
+            argv.push(payload_image.display().to_string());
+        }
+        if let Some(payload_dir) = &self.payload_dir {
+            argv.push("--payload_dir".to_string());
+            argv.push(payload_dir.display().to_string());
+        }
+        if self.skip_postinstall {
+            argv.push("--skip_postinstall".to_string());
+        }
+        if self.skip_rootfs {
+            argv.push("--skip_rootfs".to_string());
+        }
+        if self.yes {
+            argv.push("--yes".to_string());
+        }
+        if self.lab_preserve_logs {
+            argv.push("--lab_preserve_logs".to_string());
+        }
+        if let Some(pmbr_code) = &self.pmbr_code {
+            argv.push("--pmbr_code".to_string());
+            argv.push(pmbr_code.clone());
+        }
+        if let Some(target_bios) = &self.target_bios {
+            argv.push("--target_bios".to_string());
+            argv.push(target_bios.clone());
+        }
+
+        argv
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Publishes chromeos-install.sh progress as D-Bus signals on the
+//! `org.chromium.ChromeosInstall` interface so that update_engine and
+//! session UI consumers can follow an install without scraping stdout.
+
+use std::io::{BufRead, BufReader, Read};
+use std::process::Child;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use dbus::blocking::Connection;
+use dbus::Message;
+
+use crate::logging;
+
+const BUS_NAME: &str = "org.chromium.ChromeosInstall";
+const OBJECT_PATH: &str = "/org/chromium/ChromeosInstall";
+const INTERFACE: &str = "org.chromium.ChromeosInstall";
+
+/// One recognized line of progress from chromeos-install.sh, along with the
+/// fraction of the overall install it represents.
+struct Stage {
+    needle: &'static str,
+    name: &'static str,
+    fraction: f64,
+}
+
+const STAGES: &[Stage] = &[
+    Stage {
+        needle: "Copying partitions",
+        name: "partition_copy",
+        fraction: 0.25,
+    },
+    Stage {
+        needle: "Verifying rootfs",
+        name: "rootfs_verify",
+        fraction: 0.6,
+    },
+    Stage {
+        needle: "Running postinstall",
+        name: "postinstall",
+        fraction: 0.85,
+    },
+    Stage {
+        needle: "installation succeeded",
+        name: "done",
+        fraction: 1.0,
+    },
+];
+
+/// Publishes `InstallProgress`/`InstallComplete` signals over the system bus.
+pub struct InstallProgressReporter {
+    conn: Connection,
+}
+
+impl InstallProgressReporter {
+    pub fn new() -> Result<Self, dbus::Error> {
+        let conn = Connection::new_system()?;
+        conn.request_name(BUS_NAME, false, true, false)?;
+        Ok(InstallProgressReporter { conn })
+    }
+
+    /// Emits `InstallProgress(stage: string, fraction: double)`.
+    pub fn emit_progress(&self, stage: &str, fraction: f64) {
+        let msg = Message::new_signal(OBJECT_PATH, INTERFACE, "InstallProgress")
+            .expect("failed to build InstallProgress signal")
+            .append2(stage, fraction);
+        let _ = self.conn.channel().send(msg);
+    }
+
+    /// Emits the terminal `InstallComplete(success: bool, error: string)`.
+    pub fn emit_complete(&self, success: bool, error: &str) {
+        let msg = Message::new_signal(OBJECT_PATH, INTERFACE, "InstallComplete")
+            .expect("failed to build InstallComplete signal")
+            .append2(success, error);
+        let _ = self.conn.channel().send(msg);
+    }
+}
+
+/// Matches a line of chromeos-install.sh output against the known progress
+/// stages, returning the stage name and fraction if it was recognized.
+fn match_stage(line: &str) -> Option<(&'static str, f64)> {
+    STAGES
+        .iter()
+        .find(|stage| line.contains(stage.needle))
+        .map(|stage| (stage.name, stage.fraction))
+}
+
+/// Reads `child`'s piped stdout/stderr, forwarding every line to the console
+/// while translating recognized progress lines into D-Bus signals.
+///
+/// Blocks until both streams have been drained, which happens once the child
+/// closes its output (typically when it exits).
+pub fn forward_progress(child: &mut Child, reporter: Option<&InstallProgressReporter>) {
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    let (tx, rx) = mpsc::channel();
+
+    let stdout_thread = stdout.map(|out| spawn_line_reader(out, tx.clone(), false));
+    let stderr_thread = stderr.map(|err| spawn_line_reader(err, tx, true));
+
+    for (line, is_stderr) in rx {
+        if is_stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+        logging::log_child_line(&line, is_stderr);
+
+        if let Some((stage, fraction)) = match_stage(&line) {
+            if let Some(reporter) = reporter {
+                reporter.emit_progress(stage, fraction);
+            }
+        }
+    }
+
+    if let Some(t) = stdout_thread {
+        let _ = t.join();
+    }
+    if let Some(t) = stderr_thread {
+        let _ = t.join();
+    }
+}
+
+fn spawn_line_reader<R: Read + Send + 'static>(
+    reader: R,
+    tx: mpsc::Sender<(String, bool)>,
+    is_stderr: bool,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send((line, is_stderr)).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    })
+}
+
+/// Gives the D-Bus connection a moment to flush the final signal before the
+/// process exits and the bus name is released.
+pub fn wait_for_signal_flush() {
+    thread::sleep(Duration::from_millis(50));
+}
@@ -0,0 +1,153 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Post-install customization hooks.
+//!
+//! Test and board images want small, well-known tweaks applied to a freshly
+//! installed rootfs (e.g. flipping `PermitRootLogin`, overriding the
+//! auto-update server). Rather than scattering `sed` calls across ebuilds,
+//! boards can express these as a declarative TOML manifest that this module
+//! applies entry by entry, in manifest order, under the RAII
+//! [`crate::mount::MountGuard`] so the rootfs is always unmounted afterward,
+//! even if a hook fails. The first failing entry stops the run; entries
+//! applied before it are not undone.
+
+use std::fs;
+use std::io;
+use std::path::Component;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::mount::MountGuard;
+
+/// A single entry in the post-install customization manifest.
+#[derive(Debug, Deserialize)]
+pub struct Customization {
+    /// Image channel this customization applies to (e.g. "testimage-channel").
+    /// `None` means "all channels".
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// Image variant this customization applies to (e.g. "test", "base").
+    /// `None` means "all variants".
+    #[serde(default)]
+    pub variant: Option<String>,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    /// Replaces the first match of `pattern` with `replacement` in `path`.
+    FilePatch {
+        path: PathBuf,
+        pattern: String,
+        replacement: String,
+    },
+    /// Enables or disables a systemd unit, via `systemctl --root`.
+    Unit { name: String, enabled: bool },
+    /// Drops a file at `path` with the given `contents`, creating parent
+    /// directories as needed.
+    DropIn { path: PathBuf, contents: String },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HookManifest {
+    #[serde(default)]
+    pub customizations: Vec<Customization>,
+}
+
+impl HookManifest {
+    /// Loads a manifest from a TOML file.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read_to_string(path)?;
+        toml::from_str(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+}
+
+/// Applies every customization in `manifest` that's gated to `channel`/
+/// `variant` (or ungated) against the rootfs mounted at `mount.target()`.
+pub fn apply(manifest: &HookManifest, mount: &MountGuard, channel: &str, variant: &str) -> io::Result<()> {
+    for customization in &manifest.customizations {
+        if let Some(want) = &customization.channel {
+            if want != channel {
+                continue;
+            }
+        }
+        if let Some(want) = &customization.variant {
+            if want != variant {
+                continue;
+            }
+        }
+
+        apply_one(&customization.action, mount.target())?;
+    }
+    Ok(())
+}
+
+fn apply_one(action: &Action, rootfs: &Path) -> io::Result<()> {
+    match action {
+        Action::FilePatch {
+            path,
+            pattern,
+            replacement,
+        } => {
+            let full_path = rootfs.join(relative_rootfs_path(path)?);
+            let contents = fs::read_to_string(&full_path)?;
+            let re = Regex::new(pattern)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            let patched = re.replace(&contents, replacement.as_str());
+            fs::write(&full_path, patched.as_bytes())?;
+            log::info!("Applied file patch to {}", full_path.display());
+        }
+        Action::Unit { name, enabled } => {
+            let action = if *enabled { "enable" } else { "disable" };
+            let status = Command::new("systemctl")
+                .arg(format!("--root={}", rootfs.display()))
+                .arg(action)
+                .arg(name)
+                .status()?;
+            if !status.success() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("systemctl {} {} on new rootfs failed", action, name),
+                ));
+            }
+            log::info!("{} unit {} on new rootfs", action, name);
+        }
+        Action::DropIn { path, contents } => {
+            let full_path = rootfs.join(relative_rootfs_path(path)?);
+            if let Some(parent) = full_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&full_path, contents)?;
+            log::info!("Wrote drop-in file {}", full_path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a manifest-supplied `path` to one safe to join onto the mounted rootfs: strips a
+/// leading `/` (manifest paths are written rootfs-absolute) and rejects any `..` component, since
+/// a `FilePatch`/`DropIn` entry like `path = "../../etc/something"` would otherwise escape
+/// `rootfs.join(...)` entirely.
+fn relative_rootfs_path(path: &Path) -> io::Result<PathBuf> {
+    if path.components().any(|c| matches!(c, Component::ParentDir)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("customization path escapes rootfs: {}", path.display()),
+        ));
+    }
+
+    Ok(path
+        .strip_prefix("/")
+        .unwrap_or(path)
+        .components()
+        .filter(|c| !matches!(c, Component::RootDir | Component::CurDir))
+        .collect())
+}
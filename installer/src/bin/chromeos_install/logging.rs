@@ -0,0 +1,54 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Routes the installer's own messages and the chromeos-install.sh child
+//! output to syslog via the `log` crate, so a failed field install leaves a
+//! coherent record instead of lost console text.
+
+use std::str::FromStr;
+
+use log::{Level, LevelFilter};
+
+const IDENT: &str = "chromeos-install";
+
+/// Initializes syslog logging, honoring `--log-level` (falling back to
+/// `$RUST_LOG`, then `info`).
+pub fn init(log_level: Option<&str>) {
+    if let Err(e) = libchromeos::syslog::init(IDENT.to_string(), true /* log_to_stderr */) {
+        eprintln!("Failed to initialize syslog, logging to stderr only: {}", e);
+    }
+
+    let level = log_level
+        .map(str::to_string)
+        .or_else(|| std::env::var("RUST_LOG").ok())
+        .and_then(|s| LevelFilter::from_str(&s).ok())
+        .unwrap_or(LevelFilter::Info);
+
+    log::set_max_level(level);
+}
+
+/// Infers a log severity for a line of chromeos-install.sh output, since the
+/// shell script doesn't tag its own output with a level.
+fn infer_severity(line: &str) -> Level {
+    let lower = line.to_ascii_lowercase();
+    if lower.contains("error") || lower.contains("failed") || lower.contains("fatal") {
+        Level::Error
+    } else if lower.contains("warn") {
+        Level::Warn
+    } else {
+        Level::Info
+    }
+}
+
+/// Mirrors one line of child output into a timestamped syslog record.
+/// Lines from stderr are always logged as errors; stdout lines get their
+/// severity inferred from their content.
+pub fn log_child_line(line: &str, is_stderr: bool) {
+    let level = if is_stderr {
+        Level::Error
+    } else {
+        infer_severity(line)
+    };
+    log::log!(level, "{}", line);
+}
@@ -0,0 +1,196 @@
+// Copyright 2026 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! RAII mount and loopback-device bookkeeping.
+//!
+//! Ports the mount/unmount and `losetup` trap logic that
+//! `chromeos-install.sh` does with a shell `trap` into types whose `Drop`
+//! impls run the cleanup on every normal and error return. A SIGINT/SIGTERM
+//! handler is registered once so a signal during install triggers the same
+//! unwinding instead of leaving the target device mounted or a loop device
+//! attached. A panic does not reach these `Drop` impls: the memfd panic hook
+//! that `main` installs aborts the process.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nix::sys::signal::{self, SigHandler, Signal};
+use tempfile::TempDir;
+
+/// Set by the SIGINT/SIGTERM handler; checked between install steps so that
+/// a caught signal can unwind through `Drop` instead of killing the process
+/// mid-mount.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_interrupt(_signum: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs the SIGINT/SIGTERM handler. Must be called once before any
+/// `MountGuard`/`LoopDevice` is created.
+///
+/// # Safety
+///
+/// This registers a signal handler via `sigaction`, which is only safe to
+/// call from a single-threaded context at startup, before other signal
+/// handlers depending on the previous disposition are installed.
+pub fn install_signal_handler() -> nix::Result<()> {
+    // Safe because `handle_interrupt` only touches an `AtomicBool` and does
+    // not allocate or call any non-async-signal-safe function.
+    unsafe {
+        signal::signal(Signal::SIGINT, SigHandler::Handler(handle_interrupt))?;
+        signal::signal(Signal::SIGTERM, SigHandler::Handler(handle_interrupt))?;
+    }
+    Ok(())
+}
+
+/// Returns true if a SIGINT/SIGTERM arrived since the handler was installed.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// An attached loopback device that is detached via `losetup -d` when
+/// dropped.
+pub struct LoopDevice {
+    path: PathBuf,
+}
+
+impl LoopDevice {
+    /// Attaches `image` via `losetup -f --show` and returns the resulting
+    /// device.
+    pub fn attach(image: &Path) -> io::Result<Self> {
+        let output = Command::new("losetup")
+            .arg("-f")
+            .arg("--show")
+            .arg(image)
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "losetup failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+            ));
+        }
+        let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(LoopDevice {
+            path: PathBuf::from(path),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        match Command::new("losetup").arg("-d").arg(&self.path).status() {
+            Err(e) => {
+                log::warn!(
+                    "Failed to detach loop device {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+            Ok(status) if !status.success() => {
+                log::warn!("losetup -d {} exited with {}", self.path.display(), status);
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+/// A mount that is unmounted when dropped.
+pub struct MountGuard {
+    target: PathBuf,
+    // Keeps the scratch mount point alive for the guard's lifetime when one
+    // was created internally rather than supplied by the caller.
+    _scratch_dir: Option<TempDir>,
+}
+
+impl MountGuard {
+    /// Mounts `source` onto `target`.
+    pub fn mount(source: &Path, target: &Path, fs_type: &str) -> io::Result<Self> {
+        let status = Command::new("mount")
+            .arg("-t")
+            .arg(fs_type)
+            .arg(source)
+            .arg(target)
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("mount {} -> {} failed", source.display(), target.display()),
+            ));
+        }
+        Ok(MountGuard {
+            target: target.to_path_buf(),
+            _scratch_dir: None,
+        })
+    }
+
+    /// Creates a scratch mount point with `tempfile` and mounts `source`
+    /// onto it.
+    pub fn mount_scratch(source: &Path, fs_type: &str) -> io::Result<Self> {
+        let scratch_dir = TempDir::new()?;
+        let status = Command::new("mount")
+            .arg("-t")
+            .arg(fs_type)
+            .arg(source)
+            .arg(scratch_dir.path())
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "mount {} -> {} failed",
+                    source.display(),
+                    scratch_dir.path().display()
+                ),
+            ));
+        }
+        Ok(MountGuard {
+            target: scratch_dir.path().to_path_buf(),
+            _scratch_dir: Some(scratch_dir),
+        })
+    }
+
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        match Command::new("umount").arg(&self.target).status() {
+            Err(e) => {
+                log::warn!("Failed to unmount {}: {}", self.target.display(), e);
+            }
+            Ok(status) if !status.success() => {
+                log::warn!("umount {} exited with {}", self.target.display(), status);
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Returns the device node for partition 3 (ROOT-A) of `dst`, handling the
+/// `pN` separator used by `mmcblk`/`nvme`/`loop` devices.
+pub fn rootfs_partition(dst: &Path) -> PathBuf {
+    let dst = dst.to_string_lossy();
+    let needs_p = dst
+        .chars()
+        .last()
+        .map(|c| c.is_ascii_digit())
+        .unwrap_or(false);
+    if needs_p {
+        PathBuf::from(format!("{}p3", dst))
+    } else {
+        PathBuf::from(format!("{}3", dst))
+    }
+}
@@ -6,6 +6,7 @@
 
 use nix::sys::memfd::memfd_create;
 use nix::sys::memfd::MemFdCreateFlag;
+use std::backtrace::Backtrace;
 use std::borrow::Cow;
 use std::ffi::CString;
 use std::fs::File;
@@ -77,6 +78,13 @@ fn format_panic_info<W: Write>(w: &mut W, panic_info: &PanicHookInfo<'_>) -> Res
     Ok(())
 }
 
+/// Captures and writes the current call stack's backtrace, forcing capture regardless of the
+/// `RUST_BACKTRACE` env var so a panic's memfd/syslog record always includes one even on a build
+/// that doesn't set it.
+fn write_backtrace<W: Write>(w: &mut W) -> Result<()> {
+    write!(w, "\n{}", Backtrace::force_capture())
+}
+
 /// Inserts a panic handler that writes the panic info to a memfd called
 /// "RUST_PANIC_SIG" before calling the original panic handler. This
 /// makes it possible for external crash handlers to recover the panic info.
@@ -86,9 +94,22 @@ pub fn install_memfd_handler() {
         // On failure, ignore the error and call the original handler.
         if let Ok(mut panic_memfd) = create_panic_memfd() {
             let _ = format_panic_info(&mut panic_memfd, p);
+            let _ = write_backtrace(&mut panic_memfd);
             // Intentionally leak panic_memfd so it is picked up by the crash handler.
             mem::forget(panic_memfd);
         }
+
+        // Also flush the panic info through the `log` crate so that, if the
+        // process has a syslog backend installed, the panic leaves a record
+        // there too instead of only in the memfd and on stderr.
+        let mut message = Vec::new();
+        if format_panic_info(&mut message, p).is_ok() {
+            let _ = write_backtrace(&mut message);
+            if let Ok(message) = String::from_utf8(message) {
+                log::error!("{}", message);
+            }
+        }
+
         hook(p);
 
         // If this is a multithreaded program, a panic in one thread will not kill the whole
@@ -5,6 +5,15 @@
 // APIs to adjust the Quality of Service (QoS) expected for a thread or a
 // process. QoS definitions map to performance characteristics.
 
+mod pressure;
+
+pub use pressure::PressureController;
+pub use pressure::PressureLevel;
+pub use pressure::PressureThresholds;
+pub use pressure::ResourcePressureThresholds;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs::write;
 use std::io;
 
@@ -17,7 +26,7 @@ use procfs::process::Process;
 // This is used in the test.
 #[allow(dead_code)]
 const NUM_PROCESS_STATES: usize = ProcessState::Background as usize + 1;
-const NUM_THREAD_STATES: usize = ThreadState::Background as usize + 1;
+const NUM_THREAD_STATES: usize = ThreadState::Display as usize + 1;
 
 /// Scheduler QoS states of a process.
 #[repr(u8)]
@@ -49,6 +58,14 @@ pub enum ThreadState {
     Eco = 3,
     Utility = 4,
     Background = 5,
+    /// Fixed-period media/audio threads that need a guaranteed share of CPU bandwidth each
+    /// period, scheduled under `SCHED_DEADLINE` instead of the uclamp/nice-based states above.
+    Deadline = 6,
+    /// Compositor/display threads that want explicit real-time promotion. Applied as
+    /// `SCHED_FIFO` at [`DISPLAY_RT_PRIORITY`] when the caller has the headroom to be granted
+    /// real-time scheduling, falling back to a niced `SCHED_OTHER` setting otherwise -- see
+    /// [`SchedQosContext::set_thread_state`].
+    Display = 7,
 }
 
 impl TryFrom<u8> for ThreadState {
@@ -62,6 +79,8 @@ impl TryFrom<u8> for ThreadState {
             3 => Ok(Self::Eco),
             4 => Ok(Self::Utility),
             5 => Ok(Self::Background),
+            6 => Ok(Self::Deadline),
+            7 => Ok(Self::Display),
             _ => Err(()),
         }
     }
@@ -166,6 +185,100 @@ fn check_uclamp_support() -> io::Result<bool> {
     }
 }
 
+/// Reads `thread_id`'s current `sched_attr` via `sched_getattr(2)`, e.g. to capture a rollback
+/// point before changing it, or to verify a change actually took effect.
+fn get_sched_attr(thread_id: i32) -> io::Result<sched_attr> {
+    let mut attr = sched_attr::default();
+
+    // SAFETY: sched_getattr only modifies attr, which is sized via its `size` field.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_sched_getattr,
+            thread_id,
+            &mut attr as *mut sched_attr as usize,
+            std::mem::size_of::<sched_attr>() as u32,
+            0,
+        )
+    };
+
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(attr)
+    }
+}
+
+/// Re-applies `attr` to `thread_id` verbatim, to roll back a partially-applied QoS change. Best
+/// effort: the caller is already returning a different error and there is little more to do if
+/// the rollback itself fails, so failures here are silently dropped.
+fn restore_sched_attr(thread_id: i32, mut attr: sched_attr) {
+    // SAFETY: sched_setattr does not modify userspace memory.
+    unsafe {
+        libc::syscall(
+            libc::SYS_sched_setattr,
+            thread_id,
+            &mut attr as *mut sched_attr as usize,
+            0,
+        );
+    }
+}
+
+/// Compares `observed` (read back via [`get_sched_attr`]) against `expected` (what was just
+/// requested via `sched_setattr`), returning an error listing every mismatching field -- e.g. the
+/// kernel silently clamping a uclamp value or ignoring part of the request.
+fn verify_sched_attr(thread_id: i32, observed: &sched_attr, expected: &sched_attr) -> Result<()> {
+    let mut mismatches = Vec::new();
+
+    if observed.sched_policy != expected.sched_policy {
+        mismatches.push(format!(
+            "policy: expected {}, observed {}",
+            expected.sched_policy, observed.sched_policy
+        ));
+    }
+    if expected.sched_policy == libc::SCHED_FIFO as u32
+        && observed.sched_priority != expected.sched_priority
+    {
+        mismatches.push(format!(
+            "priority: expected {}, observed {}",
+            expected.sched_priority, observed.sched_priority
+        ));
+    }
+    if expected.sched_policy == libc::SCHED_OTHER as u32
+        && observed.sched_nice != expected.sched_nice
+    {
+        mismatches.push(format!(
+            "nice: expected {}, observed {}",
+            expected.sched_nice, observed.sched_nice
+        ));
+    }
+    if expected.sched_flags & SCHED_FLAG_UTIL_CLAMP_MIN != 0
+        && observed.sched_util_min != expected.sched_util_min
+    {
+        mismatches.push(format!(
+            "sched_util_min: expected {}, observed {}",
+            expected.sched_util_min, observed.sched_util_min
+        ));
+    }
+    if expected.sched_flags & SCHED_FLAG_UTIL_CLAMP_MAX != 0
+        && observed.sched_util_max != expected.sched_util_max
+    {
+        mismatches.push(format!(
+            "sched_util_max: expected {}, observed {}",
+            expected.sched_util_max, observed.sched_util_max
+        ));
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        bail!(
+            "Scheduler attributes verification failed for thread {}: {}",
+            thread_id,
+            mismatches.join("; ")
+        );
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 struct ThreadSettings {
     sched_settings: sched_attr,
@@ -187,8 +300,120 @@ const UCLAMP_MAX: u32 = 1024;
 const UCLAMP_BOOST_PERCENT: u32 = 60;
 const UCLAMP_BOOSTED_MIN: u32 = (UCLAMP_BOOST_PERCENT * UCLAMP_MAX + 50) / 100;
 
-// Thread QoS settings table
-const THREAD_SETTINGS: [ThreadSettings; NUM_THREAD_STATES] = [
+/// Runtime and period for [`ThreadState::Deadline`], sized for a worst case 2ms callback running
+/// every 10ms -- a typical fixed-period media/audio processing cadence.
+const DEADLINE_RUNTIME_NS: u64 = 2_000_000;
+const DEADLINE_PERIOD_NS: u64 = 10_000_000;
+
+/// `SCHED_FIFO` priority requested for [`ThreadState::Display`]. Kept above
+/// [`ThreadState::UrgentBursty`]'s priority so a promoted display thread preempts it.
+const DISPLAY_RT_PRIORITY: u32 = 9;
+
+/// `sched_attr` applied to a [`ThreadState::Display`] thread when real-time scheduling cannot be
+/// granted (the caller lacks `CAP_SYS_NICE` or is over `RLIMIT_RTPRIO`): a niced `SCHED_OTHER`
+/// setting matching [`ThreadState::Urgent`]'s nice value, rather than failing the request.
+const DISPLAY_RT_FALLBACK_SCHED_ATTR: sched_attr = sched_attr {
+    sched_nice: -8,
+    ..sched_attr::default()
+};
+
+/// A per-[`ThreadState`] override of the default `sched_util_min`/`sched_util_max` uclamp values,
+/// e.g. parsed from a board-specific config file. A `None` field keeps the corresponding
+/// [`DEFAULT_THREAD_SETTINGS`] value for that state.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct SchedUtilHint {
+    pub sched_util_min: Option<u32>,
+    pub sched_util_max: Option<u32>,
+}
+
+/// Board-specific `sched_util` hints, one per [`ThreadState`], passed to [`SchedQosContext::new`].
+pub type SchedUtilHints = [SchedUtilHint; NUM_THREAD_STATES];
+
+/// Parses per-board `sched_util` hints out of a simple config file, one override per line:
+/// `<thread state> <sched_util_min> <sched_util_max>` (e.g. `urgent 900 1024`). Use `-` for a
+/// field that should keep the default value. Blank lines and lines starting with `#` are ignored.
+pub fn parse_sched_util_hints(config: &str) -> Result<SchedUtilHints> {
+    let mut hints = [SchedUtilHint::default(); NUM_THREAD_STATES];
+
+    for (lineno, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let state_name = fields
+            .next()
+            .ok_or_else(|| anyhow!("line {}: missing thread state", lineno + 1))?;
+        let min = fields
+            .next()
+            .ok_or_else(|| anyhow!("line {}: missing sched_util_min", lineno + 1))?;
+        let max = fields
+            .next()
+            .ok_or_else(|| anyhow!("line {}: missing sched_util_max", lineno + 1))?;
+
+        let state = thread_state_from_name(state_name)
+            .ok_or_else(|| anyhow!("line {}: unknown thread state '{}'", lineno + 1, state_name))?;
+
+        hints[state as usize] = SchedUtilHint {
+            sched_util_min: parse_sched_util_hint_field(min)?,
+            sched_util_max: parse_sched_util_hint_field(max)?,
+        };
+    }
+
+    Ok(hints)
+}
+
+fn parse_sched_util_hint_field(field: &str) -> Result<Option<u32>> {
+    if field == "-" {
+        Ok(None)
+    } else {
+        field
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("invalid sched_util value '{}': {}", field, e))
+    }
+}
+
+fn thread_state_from_name(name: &str) -> Option<ThreadState> {
+    match name {
+        "urgent_bursty" => Some(ThreadState::UrgentBursty),
+        "urgent" => Some(ThreadState::Urgent),
+        "balanced" => Some(ThreadState::Balanced),
+        "eco" => Some(ThreadState::Eco),
+        "utility" => Some(ThreadState::Utility),
+        "background" => Some(ThreadState::Background),
+        "deadline" => Some(ThreadState::Deadline),
+        "display" => Some(ThreadState::Display),
+        _ => None,
+    }
+}
+
+/// Builds the thread settings table used by a [`SchedQosContext`]: [`DEFAULT_THREAD_SETTINGS`]
+/// with `hints` (if any) applied on top, clamping every overridden `sched_util_min`/
+/// `sched_util_max` to `[0, UCLAMP_MAX]`.
+fn build_thread_settings(hints: Option<&SchedUtilHints>) -> [ThreadSettings; NUM_THREAD_STATES] {
+    let mut settings = DEFAULT_THREAD_SETTINGS;
+
+    let Some(hints) = hints else {
+        return settings;
+    };
+
+    for (setting, hint) in settings.iter_mut().zip(hints.iter()) {
+        if let Some(sched_util_min) = hint.sched_util_min {
+            setting.sched_settings.sched_util_min = sched_util_min.min(UCLAMP_MAX);
+        }
+        if let Some(sched_util_max) = hint.sched_util_max {
+            setting.sched_settings.sched_util_max = sched_util_max.min(UCLAMP_MAX);
+        }
+    }
+
+    settings
+}
+
+// Default thread QoS settings table, used for any state not overridden by a board's
+// `sched_util` hints.
+const DEFAULT_THREAD_SETTINGS: [ThreadSettings; NUM_THREAD_STATES] = [
     // UrgentBursty
     ThreadSettings {
         sched_settings: sched_attr {
@@ -244,6 +469,28 @@ const THREAD_SETTINGS: [ThreadSettings; NUM_THREAD_STATES] = [
         cpuset: CpuSelection::Efficient,
         prefer_idle: false,
     },
+    // Deadline
+    ThreadSettings {
+        sched_settings: sched_attr {
+            sched_policy: libc::SCHED_DEADLINE as u32,
+            sched_runtime: DEADLINE_RUNTIME_NS,
+            sched_deadline: DEADLINE_PERIOD_NS,
+            sched_period: DEADLINE_PERIOD_NS,
+            ..sched_attr::default()
+        },
+        cpuset: CpuSelection::All,
+        prefer_idle: true,
+    },
+    // Display
+    ThreadSettings {
+        sched_settings: sched_attr {
+            sched_policy: libc::SCHED_FIFO as u32,
+            sched_priority: DISPLAY_RT_PRIORITY,
+            ..sched_attr::default()
+        },
+        cpuset: CpuSelection::All,
+        prefer_idle: true,
+    },
 ];
 
 fn is_same_process(process_id: i32, thread_id: i32) -> Result<bool> {
@@ -257,22 +504,61 @@ fn is_same_process(process_id: i32, thread_id: i32) -> Result<bool> {
     Ok(stat.tgid == process_id)
 }
 
+/// Lists the thread (task) ids currently belonging to `process_id`. Threads that exit mid-listing
+/// are silently dropped rather than failing the whole listing.
+fn list_thread_ids(process_id: i32) -> Result<Vec<i32>> {
+    let proc =
+        Process::new(process_id).map_err(|e| anyhow!("Failed to find process, error: {}", e))?;
+    let tasks = proc
+        .tasks()
+        .map_err(|e| anyhow!("Failed to list threads of process, error: {}", e))?;
+
+    Ok(tasks
+        .filter_map(|task| task.ok())
+        .map(|task| task.tid)
+        .collect())
+}
+
 pub struct SchedQosContext {
     uclamp_support: bool,
+    thread_settings: [ThreadSettings; NUM_THREAD_STATES],
+    /// The most recently requested [`ThreadState`] for each `(process_id, thread_id)`, tracked so
+    /// a process cascaded into [`ProcessState::Background`] can later be restored to the states
+    /// its threads had before being demoted.
+    thread_states: HashMap<(i32, i32), ThreadState>,
+    /// Processes currently demoted to [`ProcessState::Background`] with thread cascading on, so a
+    /// later [`SchedQosContext::set_thread_state`] call for one of their threads -- e.g. a newly
+    /// spawned thread -- applies [`ThreadState::Background`] instead of the caller's requested
+    /// state, while still recording the requested state in `thread_states` for the eventual
+    /// restore.
+    backgrounded_processes: HashSet<i32>,
 }
 
 impl SchedQosContext {
-    pub fn new() -> io::Result<Self> {
+    /// Creates a context using [`DEFAULT_THREAD_SETTINGS`], with any state named in
+    /// `sched_util_hints` overridden by the board-specific value there (e.g. parsed via
+    /// [`parse_sched_util_hints`]). Pass `None` to use the defaults unmodified.
+    pub fn new(sched_util_hints: Option<&SchedUtilHints>) -> io::Result<Self> {
         Ok(Self {
             uclamp_support: check_uclamp_support()?,
+            thread_settings: build_thread_settings(sched_util_hints),
+            thread_states: HashMap::new(),
+            backgrounded_processes: HashSet::new(),
         })
     }
 
+    /// Moves `process_id` into `process_state`'s cgroup.
+    ///
+    /// If `cascade_to_threads` is set, moving to [`ProcessState::Background`] additionally demotes
+    /// every thread of the process to [`ThreadState::Background`], saving each thread's last
+    /// requested [`ThreadState`]; moving back to [`ProcessState::Normal`] restores those saved
+    /// states. This is opt-in because not every caller wants per-thread QoS overridden by process
+    /// state.
     pub fn set_process_state(
-        // TODO(kawasin): Make this mut to update internal state mapping.
-        &self,
+        &mut self,
         process_id: i32,
         process_state: ProcessState,
+        cascade_to_threads: bool,
     ) -> Result<()> {
         match process_state {
             ProcessState::Normal => write(CGROUP_NORMAL, process_id.to_string())
@@ -281,28 +567,148 @@ impl SchedQosContext {
                 .context(format!("Failed to write to {}", CGROUP_BACKGROUND))?,
         }
 
+        if cascade_to_threads {
+            match process_state {
+                ProcessState::Background => self.demote_process_threads(process_id)?,
+                ProcessState::Normal => self.restore_process_threads(process_id)?,
+            }
+        }
+
         Ok(())
     }
 
+    /// Forces every thread of `process_id` to [`ThreadState::Background`], without touching the
+    /// saved [`ThreadState`] each thread was last explicitly set to.
+    fn demote_process_threads(&mut self, process_id: i32) -> Result<()> {
+        self.backgrounded_processes.insert(process_id);
+
+        for thread_id in list_thread_ids(process_id)? {
+            self.apply_thread_state(process_id, thread_id, ThreadState::Background, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Restores every still-alive thread of `process_id` to its last explicitly requested
+    /// [`ThreadState`], dropping the saved state of any thread that exited in the meantime.
+    /// Threads that were never explicitly set have no saved state and are left as-is.
+    fn restore_process_threads(&mut self, process_id: i32) -> Result<()> {
+        self.backgrounded_processes.remove(&process_id);
+
+        let live_threads: HashSet<i32> = list_thread_ids(process_id)?.into_iter().collect();
+
+        for &thread_id in &live_threads {
+            if let Some(&state) = self.thread_states.get(&(process_id, thread_id)) {
+                self.apply_thread_state(process_id, thread_id, state, false)?;
+            }
+        }
+
+        self.thread_states
+            .retain(|&(pid, tid), _| pid != process_id || live_threads.contains(&tid));
+
+        Ok(())
+    }
+
+    /// Sets the [`ThreadState`] of `thread_id`, a thread of `process_id`.
+    ///
+    /// If `process_id` is currently cascaded into [`ProcessState::Background`] (see
+    /// [`SchedQosContext::set_process_state`]), `thread_state` is recorded for restoration but
+    /// [`ThreadState::Background`] is applied instead, so newly spawned threads of a backgrounded
+    /// process inherit Background like their siblings.
+    ///
+    /// If `verify` is set, the settings are read back with `sched_getattr(2)` after being applied
+    /// and compared against what was requested, catching silent kernel clamping; and if that
+    /// check or any of the later cpuset/`latency_sensitive` writes fails, `thread_id`'s scheduler
+    /// attributes are rolled back to what they were before this call. Only the scheduler
+    /// attributes are rolled back this way -- a cpuset write that succeeds and is followed by a
+    /// failing `latency_sensitive` write leaves the thread's cpuset assignment changed; there is
+    /// no "previous cpuset" captured to restore it to.
+    ///
+    /// Returns `true` if `thread_state`'s settings were applied as configured, or `false` if
+    /// [`ThreadState::Display`]'s real-time request had to be downgraded to its `SCHED_OTHER`
+    /// fallback because the caller lacks the `CAP_SYS_NICE`/`RLIMIT_RTPRIO` headroom for
+    /// `SCHED_FIFO`; callers can log this to surface which scheduling path a display thread got.
+    /// Always `true` for every other state.
     pub fn set_thread_state(
-        // TODO(kawasin): Make this mut to update internal state mapping.
+        &mut self,
+        process_id: i32,
+        thread_id: i32,
+        thread_state: ThreadState,
+        verify: bool,
+    ) -> Result<bool> {
+        self.thread_states
+            .insert((process_id, thread_id), thread_state);
+
+        let effective_state = if self.backgrounded_processes.contains(&process_id) {
+            ThreadState::Background
+        } else {
+            thread_state
+        };
+
+        self.apply_thread_state(process_id, thread_id, effective_state, verify)
+    }
+
+    /// Applies `thread_state`'s scheduler, cpuset, and latency-sensitive settings to `thread_id`
+    /// directly, without any bookkeeping for the Background cascade. See
+    /// [`SchedQosContext::set_thread_state`] for the meaning of `verify` and the returned `bool`.
+    fn apply_thread_state(
         &self,
         process_id: i32,
         thread_id: i32,
         thread_state: ThreadState,
-    ) -> Result<()> {
+        verify: bool,
+    ) -> Result<bool> {
+        let thread_settings = self.thread_settings[thread_state as usize];
+        self.apply_thread_settings(
+            process_id,
+            thread_id,
+            thread_state,
+            &thread_settings,
+            verify,
+        )
+    }
+
+    /// Applies `thread_settings` to `thread_id` directly, without any bookkeeping for the
+    /// Background cascade. `thread_state` is only consulted to pick the [`ThreadState::Display`]
+    /// RT-fallback path; `thread_settings` need not be [`SchedQosContext::thread_settings`]'s
+    /// stock entry for `thread_state` -- e.g. the PSI pressure controller ([`crate::pressure`])
+    /// passes in a copy with `sched_util_max` capped tighter than the configured default. See
+    /// [`SchedQosContext::set_thread_state`] for the meaning of `verify` and the returned `bool`.
+    pub(crate) fn apply_thread_settings(
+        &self,
+        process_id: i32,
+        thread_id: i32,
+        thread_state: ThreadState,
+        thread_settings: &ThreadSettings,
+        verify: bool,
+    ) -> Result<bool> {
         // Validate thread_id is a thread of process_id
         if !is_same_process(process_id, thread_id)? {
             bail!("Thread does not belong to process");
         }
 
-        let thread_settings = &THREAD_SETTINGS[thread_state as usize];
+        // Captured before any change so a verification failure or a later cpuset/
+        // latency_sensitive write failure can roll the thread's scheduler attributes back to what
+        // they were before this call. Note this only covers the scheduler attributes -- the
+        // cpuset write below has no equivalent "previous cpuset" capture, so a cpuset change that
+        // succeeds ahead of a later failure is not reverted.
+        let previous_sched_attr = if verify {
+            Some(
+                get_sched_attr(thread_id)
+                    .context("Failed to capture previous scheduler attributes for rollback")?,
+            )
+        } else {
+            None
+        };
+
         let mut temp_sched_attr = thread_settings.sched_settings;
+        let is_deadline = temp_sched_attr.sched_policy == libc::SCHED_DEADLINE as u32;
 
         // Setting SCHED_FLAG_UTIL_CLAMP_MIN or SCHED_FLAG_UTIL_CLAMP_MAX should
         // be avoided if kernel does not support uclamp. Otherwise
-        // sched_setattr(2) fails as EOPNOTSUPP.
-        if self.uclamp_support {
+        // sched_setattr(2) fails as EOPNOTSUPP. SCHED_DEADLINE rejects the uclamp
+        // flags outright (EINVAL), so they are never set for it either.
+        if self.uclamp_support && !is_deadline {
             temp_sched_attr.sched_flags |= SCHED_FLAG_UTIL_CLAMP_MIN | SCHED_FLAG_UTIL_CLAMP_MAX;
         };
 
@@ -314,20 +720,91 @@ impl SchedQosContext {
                 0,
             )
         };
+
+        let mut rt_granted = true;
+
         if res < 0 {
-            bail!(
-                "Failed to set scheduler attributes, error={}",
-                io::Error::last_os_error()
-            );
+            let err = io::Error::last_os_error();
+            if is_deadline {
+                match err.raw_os_error() {
+                    Some(libc::EBUSY) => bail!(
+                        "SCHED_DEADLINE admission control rejected thread {}: not enough \
+                         spare bandwidth for runtime={}ns/period={}ns",
+                        thread_id,
+                        temp_sched_attr.sched_runtime,
+                        temp_sched_attr.sched_period
+                    ),
+                    Some(libc::EINVAL) => bail!(
+                        "SCHED_DEADLINE admission control rejected thread {}: invalid \
+                         runtime={}ns/deadline={}ns/period={}ns",
+                        thread_id,
+                        temp_sched_attr.sched_runtime,
+                        temp_sched_attr.sched_deadline,
+                        temp_sched_attr.sched_period
+                    ),
+                    _ => {}
+                }
+                bail!("Failed to set scheduler attributes, error={}", err);
+            } else if thread_state == ThreadState::Display
+                && err.raw_os_error() == Some(libc::EPERM)
+            {
+                // No CAP_SYS_NICE or over RLIMIT_RTPRIO: fall back to a niced SCHED_OTHER
+                // "urgent" setting rather than failing the request outright.
+                temp_sched_attr = DISPLAY_RT_FALLBACK_SCHED_ATTR;
+                if self.uclamp_support {
+                    temp_sched_attr.sched_flags |=
+                        SCHED_FLAG_UTIL_CLAMP_MIN | SCHED_FLAG_UTIL_CLAMP_MAX;
+                }
+
+                // SAFETY: sched_setattr does not modify userspace memory.
+                let fallback_res = unsafe {
+                    libc::syscall(
+                        libc::SYS_sched_setattr,
+                        thread_id,
+                        &mut temp_sched_attr as *mut sched_attr as usize,
+                        0,
+                    )
+                };
+                if fallback_res < 0 {
+                    bail!(
+                        "Failed to set fallback scheduler attributes for display thread {}, \
+                         error={}",
+                        thread_id,
+                        io::Error::last_os_error()
+                    );
+                }
+                rt_granted = false;
+            } else {
+                bail!("Failed to set scheduler attributes, error={}", err);
+            }
+        }
+
+        if verify {
+            let observed = get_sched_attr(thread_id)
+                .context("Failed to read back scheduler attributes for verification")?;
+            if let Err(mismatch) = verify_sched_attr(thread_id, &observed, &temp_sched_attr) {
+                if let Some(previous) = previous_sched_attr {
+                    restore_sched_attr(thread_id, previous);
+                }
+                return Err(mismatch);
+            }
         }
 
         // Apply the cpuset setting
-        match thread_settings.cpuset {
+        let cpuset_result = match thread_settings.cpuset {
             CpuSelection::All => write(CPUSET_ALL, thread_id.to_string())
-                .context(format!("Failed to write to {}", CPUSET_ALL))?,
+                .with_context(|| format!("Failed to write to {}", CPUSET_ALL)),
             CpuSelection::Efficient => write(CPUSET_EFFICIENT, thread_id.to_string())
-                .context(format!("Failed to write to {}", CPUSET_EFFICIENT))?,
+                .with_context(|| format!("Failed to write to {}", CPUSET_EFFICIENT)),
         };
+        if let Err(err) = cpuset_result {
+            if verify {
+                if let Some(previous) = previous_sched_attr {
+                    restore_sched_attr(thread_id, previous);
+                }
+            }
+            return Err(err);
+        }
 
         // Apply latency sensitive. Latency_sensitive will prefer idle cores.
         // This is a patch not yet in upstream(http://crrev/c/2981472)
@@ -336,11 +813,67 @@ impl SchedQosContext {
 
         if std::path::Path::new(&latency_sensitive_file).exists() {
             let value = if thread_settings.prefer_idle { 1 } else { 0 };
-            write(&latency_sensitive_file, value.to_string())
-                .context(format!("Failed to write to {}", latency_sensitive_file))?;
+            if let Err(err) = write(&latency_sensitive_file, value.to_string())
+                .with_context(|| format!("Failed to write to {}", latency_sensitive_file))
+            {
+                if verify {
+                    if let Some(previous) = previous_sched_attr {
+                        restore_sched_attr(thread_id, previous);
+                    }
+                }
+                return Err(err);
+            }
         }
 
-        Ok(())
+        Ok(rt_granted)
+    }
+
+    /// Caps `sched_util_max` at `util_max_cap` for every currently-tracked thread whose last
+    /// explicitly requested state is [`ThreadState::Background`] or [`ThreadState::Utility`] --
+    /// i.e. never a thread a caller set to something more urgent. Used by the PSI pressure
+    /// controller to shed background CPU/memory pressure. A thread that has since exited is
+    /// dropped from the tracked set instead of failing the whole sweep.
+    pub(crate) fn tighten_background_and_utility(&mut self, util_max_cap: u32) {
+        let targets: Vec<(i32, i32, ThreadState)> = self
+            .thread_states
+            .iter()
+            .filter(|(_, &state)| matches!(state, ThreadState::Background | ThreadState::Utility))
+            .map(|(&(process_id, thread_id), &state)| (process_id, thread_id, state))
+            .collect();
+
+        for (process_id, thread_id, state) in targets {
+            let mut settings = self.thread_settings[state as usize];
+            settings.sched_settings.sched_util_max =
+                settings.sched_settings.sched_util_max.min(util_max_cap);
+
+            if self
+                .apply_thread_settings(process_id, thread_id, state, &settings, false)
+                .is_err()
+            {
+                self.thread_states.remove(&(process_id, thread_id));
+            }
+        }
+    }
+
+    /// Restores every currently-tracked [`ThreadState::Background`]/[`ThreadState::Utility`]
+    /// thread to its configured, untightened settings. See
+    /// [`SchedQosContext::tighten_background_and_utility`].
+    pub(crate) fn relax_background_and_utility(&mut self) {
+        let targets: Vec<(i32, i32, ThreadState)> = self
+            .thread_states
+            .iter()
+            .filter(|(_, &state)| matches!(state, ThreadState::Background | ThreadState::Utility))
+            .map(|(&(process_id, thread_id), &state)| (process_id, thread_id, state))
+            .collect();
+
+        for (process_id, thread_id, state) in targets {
+            if self
+                .apply_thread_state(process_id, thread_id, state, false)
+                .is_err()
+            {
+                self.thread_states.remove(&(process_id, thread_id));
+            }
+        }
     }
 }
 
@@ -366,10 +899,82 @@ mod tests {
             ThreadState::Eco,
             ThreadState::Utility,
             ThreadState::Background,
+            ThreadState::Deadline,
+            ThreadState::Display,
         ] {
             assert_eq!(state, ThreadState::try_from(state as u8).unwrap());
         }
 
         assert!(ThreadState::try_from(NUM_THREAD_STATES as u8).is_err());
     }
+
+    #[test]
+    fn test_build_thread_settings_default() {
+        let settings = build_thread_settings(None);
+        assert_eq!(settings, DEFAULT_THREAD_SETTINGS);
+    }
+
+    #[test]
+    fn test_build_thread_settings_override_and_clamp() {
+        let mut hints = [SchedUtilHint::default(); NUM_THREAD_STATES];
+        hints[ThreadState::Urgent as usize] = SchedUtilHint {
+            sched_util_min: Some(900),
+            sched_util_max: Some(UCLAMP_MAX + 100),
+        };
+
+        let settings = build_thread_settings(Some(&hints));
+
+        assert_eq!(
+            settings[ThreadState::Urgent as usize]
+                .sched_settings
+                .sched_util_min,
+            900
+        );
+        assert_eq!(
+            settings[ThreadState::Urgent as usize]
+                .sched_settings
+                .sched_util_max,
+            UCLAMP_MAX
+        );
+        // Unmentioned states keep their defaults.
+        assert_eq!(
+            settings[ThreadState::Balanced as usize],
+            DEFAULT_THREAD_SETTINGS[ThreadState::Balanced as usize]
+        );
+    }
+
+    #[test]
+    fn test_parse_sched_util_hints() {
+        let hints =
+            parse_sched_util_hints("# comment\n\nurgent 900 -\nbackground - 512\n").unwrap();
+
+        assert_eq!(
+            hints[ThreadState::Urgent as usize],
+            SchedUtilHint {
+                sched_util_min: Some(900),
+                sched_util_max: None
+            }
+        );
+        assert_eq!(
+            hints[ThreadState::Background as usize],
+            SchedUtilHint {
+                sched_util_min: None,
+                sched_util_max: Some(512)
+            }
+        );
+        assert_eq!(
+            hints[ThreadState::Balanced as usize],
+            SchedUtilHint::default()
+        );
+    }
+
+    #[test]
+    fn test_parse_sched_util_hints_unknown_state() {
+        assert!(parse_sched_util_hints("not_a_state 1 2\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_sched_util_hints_bad_value() {
+        assert!(parse_sched_util_hints("urgent abc 2\n").is_err());
+    }
 }
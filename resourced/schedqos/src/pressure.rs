@@ -0,0 +1,255 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A PSI-driven controller that automatically tightens [`ThreadState::Background`]/
+//! [`ThreadState::Utility`] threads under memory/CPU pressure and relaxes them again once
+//! pressure subsides, touching only threads already tracked by a [`SchedQosContext`] so
+//! user-requested more-urgent threads are never throttled.
+//!
+//! PSI documentation: <https://docs.kernel.org/accounting/psi.html>
+
+use std::fs::read_to_string;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::SchedQosContext;
+use crate::UCLAMP_MAX;
+
+const PRESSURE_CPU_PATH: &str = "/proc/pressure/cpu";
+const PRESSURE_MEMORY_PATH: &str = "/proc/pressure/memory";
+
+/// `sched_util_max` ceiling applied to Background/Utility threads while [`PressureLevel::Tight`]
+/// -- half of `UCLAMP_MAX`, leaving background work enough headroom to still make forward
+/// progress without competing as hard for capacity.
+const TIGHTENED_UCLAMP_MAX: u32 = UCLAMP_MAX / 2;
+
+const LEVEL_NORMAL: u8 = 0;
+const LEVEL_TIGHT: u8 = 1;
+
+/// Coarse snapshot of whether a [`PressureController`] currently considers the system under
+/// memory/CPU pressure, for metrics reporting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PressureLevel {
+    /// Background/Utility threads are running at their normal, configured settings.
+    Normal,
+    /// A resource's `some avg10` crossed its tighten threshold; Background/Utility threads are
+    /// running with a capped `sched_util_max` until pressure relaxes again.
+    Tight,
+}
+
+/// A tighten/relax threshold pair for one PSI resource's `some avg10` stall percentage
+/// (`0.0..=100.0`). `tighten_pct` must be greater than `relax_pct`, leaving a hysteresis band so
+/// the controller doesn't flap between levels when pressure hovers near one threshold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResourcePressureThresholds {
+    pub tighten_pct: f64,
+    pub relax_pct: f64,
+}
+
+impl ResourcePressureThresholds {
+    fn validate(&self, resource: &str) -> Result<()> {
+        if self.tighten_pct <= self.relax_pct {
+            bail!(
+                "{} tighten threshold ({}) must be greater than its relax threshold ({})",
+                resource,
+                self.tighten_pct,
+                self.relax_pct
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Configures a [`PressureController`]: how often to poll `/proc/pressure/{cpu,memory}` and the
+/// hysteresis band for each resource.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PressureThresholds {
+    pub poll_interval: Duration,
+    pub cpu: ResourcePressureThresholds,
+    pub memory: ResourcePressureThresholds,
+}
+
+impl PressureThresholds {
+    fn validate(&self) -> Result<()> {
+        self.cpu.validate("cpu")?;
+        self.memory.validate("memory")?;
+        Ok(())
+    }
+}
+
+/// Reads `path` (a `/proc/pressure/<resource>` file) and returns its `some` line's `avg10`.
+fn read_some_avg10(path: &str) -> Result<f64> {
+    let contents = read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+
+    contents
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            if fields.next()? != "some" {
+                return None;
+            }
+            fields.find_map(|field| {
+                let (key, value) = field.split_once('=')?;
+                if key != "avg10" {
+                    return None;
+                }
+                value.parse().ok()
+            })
+        })
+        .with_context(|| format!("No 'some avg10' field found in {}", path))
+}
+
+/// Background, autonomous PSI-driven tightening of Background/Utility threads for one
+/// [`SchedQosContext`]. Create with [`PressureController::new`], then
+/// [`PressureController::start`] its poll loop; dropping the controller (or calling
+/// [`PressureController::stop`]) stops it.
+pub struct PressureController {
+    context: Arc<Mutex<SchedQosContext>>,
+    thresholds: PressureThresholds,
+    level: Arc<AtomicU8>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl PressureController {
+    /// Creates a controller for `context`. Fails if `thresholds` doesn't leave a tighten-above-
+    /// relax-below hysteresis band for both resources.
+    pub fn new(
+        context: Arc<Mutex<SchedQosContext>>,
+        thresholds: PressureThresholds,
+    ) -> Result<Self> {
+        thresholds.validate()?;
+
+        Ok(Self {
+            context,
+            thresholds,
+            level: Arc::new(AtomicU8::new(LEVEL_NORMAL)),
+            stop: Arc::new(AtomicBool::new(false)),
+            worker: None,
+        })
+    }
+
+    /// The most recently observed [`PressureLevel`], for metrics. `Normal` until the first poll.
+    pub fn level(&self) -> PressureLevel {
+        match self.level.load(Ordering::Relaxed) {
+            LEVEL_TIGHT => PressureLevel::Tight,
+            _ => PressureLevel::Normal,
+        }
+    }
+
+    /// Starts the poll loop on a background thread. A no-op if already started.
+    pub fn start(&mut self) {
+        if self.worker.is_some() {
+            return;
+        }
+
+        self.stop.store(false, Ordering::Relaxed);
+
+        let context = self.context.clone();
+        let level = self.level.clone();
+        let stop = self.stop.clone();
+        let thresholds = self.thresholds;
+
+        self.worker = Some(thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                // A single bad poll (e.g. PSI unsupported on this kernel) shouldn't end the
+                // loop -- just try again next interval.
+                let _ = poll_once(&context, &thresholds, &level);
+                thread::sleep(thresholds.poll_interval);
+            }
+        }));
+    }
+
+    /// Stops the poll loop and waits for the background thread to exit. A no-op if not started.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for PressureController {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn poll_once(
+    context: &Mutex<SchedQosContext>,
+    thresholds: &PressureThresholds,
+    level: &AtomicU8,
+) -> Result<()> {
+    let cpu_avg10 = read_some_avg10(PRESSURE_CPU_PATH)?;
+    let memory_avg10 = read_some_avg10(PRESSURE_MEMORY_PATH)?;
+
+    let currently_tight = level.load(Ordering::Relaxed) == LEVEL_TIGHT;
+    let should_tighten =
+        cpu_avg10 >= thresholds.cpu.tighten_pct || memory_avg10 >= thresholds.memory.tighten_pct;
+    let should_relax =
+        cpu_avg10 < thresholds.cpu.relax_pct && memory_avg10 < thresholds.memory.relax_pct;
+
+    let mut context = context
+        .lock()
+        .map_err(|_| anyhow!("PressureController's SchedQosContext mutex was poisoned"))?;
+
+    if !currently_tight && should_tighten {
+        context.tighten_background_and_utility(TIGHTENED_UCLAMP_MAX);
+        level.store(LEVEL_TIGHT, Ordering::Relaxed);
+    } else if currently_tight && should_relax {
+        context.relax_background_and_utility();
+        level.store(LEVEL_NORMAL, Ordering::Relaxed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resource_pressure_thresholds_validate() {
+        ResourcePressureThresholds {
+            tighten_pct: 20.0,
+            relax_pct: 5.0,
+        }
+        .validate("cpu")
+        .unwrap();
+
+        assert!(ResourcePressureThresholds {
+            tighten_pct: 5.0,
+            relax_pct: 20.0,
+        }
+        .validate("cpu")
+        .is_err());
+
+        assert!(ResourcePressureThresholds {
+            tighten_pct: 10.0,
+            relax_pct: 10.0,
+        }
+        .validate("cpu")
+        .is_err());
+    }
+
+    #[test]
+    fn test_read_some_avg10() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cpu");
+        std::fs::write(&path, "some avg10=12.34 avg60=5.00 avg300=1.00 total=100\n").unwrap();
+
+        assert_eq!(read_some_avg10(path.to_str().unwrap()).unwrap(), 12.34);
+    }
+}
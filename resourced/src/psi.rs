@@ -17,6 +17,42 @@ use anyhow::{bail, Context, Result};
 use tokio::io::unix::AsyncFd;
 use tokio::time::timeout;
 
+/// The resource a PSI monitor tracks, i.e. which `/proc/pressure/<resource>` file to open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsiResource {
+    Cpu,
+    Memory,
+    Io,
+}
+
+impl PsiResource {
+    fn path(&self) -> &'static str {
+        match self {
+            PsiResource::Cpu => "/proc/pressure/cpu",
+            PsiResource::Memory => "/proc/pressure/memory",
+            PsiResource::Io => "/proc/pressure/io",
+        }
+    }
+}
+
+/// Which tracking type to arm a trigger for. `Some` fires when some tasks are stalled, `Full`
+/// fires only when all non-idle tasks are stalled. The kernel doesn't support `Full` for the CPU
+/// resource -- there's no "all tasks blocked on CPU" state distinct from `Some`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsiKind {
+    Some,
+    Full,
+}
+
+impl PsiKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PsiKind::Some => "some",
+            PsiKind::Full => "full",
+        }
+    }
+}
+
 // Converts the libc error return to Result::Err.
 fn cerr(t: libc::c_int) -> Result<libc::c_int> {
     match t {
@@ -42,62 +78,135 @@ fn create_epoll_pri(file: &File) -> Result<AsyncFd<RawFd>> {
     AsyncFd::new(fd).context("Failed to Create AsyncFd")
 }
 
-/// Wait for PSI monitor event that memory stall time exceeded a certain threshold in recent time
-/// window. Returns Ok(true) if the PSI monitor event is triggered. Returns Ok(false) when waiting
-/// time exceeded `max_waiting_ms`.
-///
-/// # Arguments
-///
-/// * `stall_ms` - Memory stall time in millisecond to trigger the PSI monitor event.
-/// * `window_ms` - Time window in millisecond to check the stall threshold.
-/// * `min_waiting_ms` - Minimal waiting time in millisecond. Used to prevent too frequent
-/// triggering.
-/// * `max_waiting_ms` - Maximal waiting time in millisecond. Used to prevent indefinite waiting.
-///
-/// PSI monitor documentation: https://docs.kernel.org/accounting/psi.html#monitoring-for-pressure-thresholds
-pub async fn wait_psi_monitor_memory_event(
+/// One resource's `avg10`/`avg60`/`avg300`/`total` pressure averages, parsed from the `some` or
+/// `full` line of a `/proc/pressure/<resource>` file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PsiPressureLine {
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
+    pub total: u64,
+}
+
+fn parse_pressure_line(line: &str, kind: PsiKind) -> Option<PsiPressureLine> {
+    let mut fields = line.split_whitespace();
+    if fields.next()? != kind.as_str() {
+        return None;
+    }
+
+    let mut avg10 = None;
+    let mut avg60 = None;
+    let mut avg300 = None;
+    let mut total = None;
+    for field in fields {
+        let (key, value) = field.split_once('=')?;
+        match key {
+            "avg10" => avg10 = value.parse().ok(),
+            "avg60" => avg60 = value.parse().ok(),
+            "avg300" => avg300 = value.parse().ok(),
+            "total" => total = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    Some(PsiPressureLine {
+        avg10: avg10?,
+        avg60: avg60?,
+        avg300: avg300?,
+        total: total?,
+    })
+}
+
+/// Reads and parses `resource`'s current `kind` pressure averages directly, with no monitor
+/// trigger involved.
+pub fn read_psi_pressure(resource: PsiResource, kind: PsiKind) -> Result<PsiPressureLine> {
+    let contents = std::fs::read_to_string(resource.path())
+        .with_context(|| format!("Failed to read {}", resource.path()))?;
+
+    contents
+        .lines()
+        .find_map(|line| parse_pressure_line(line, kind))
+        .with_context(|| format!("No '{}' line found in {}", kind.as_str(), resource.path()))
+}
+
+/// Opens `resource`'s pressure file and arms a monitor trigger on it, returning the opened file
+/// so the caller can register it with epoll. Shared by [`wait_psi_monitor_event`] and
+/// [`PsiMonitorSet`], which each need their own fd because the kernel associates one trigger per
+/// open file descriptor.
+fn arm_psi_trigger(
+    resource: PsiResource,
+    kind: PsiKind,
     stall_ms: u64,
     window_ms: u64,
-    min_waiting_ms: u64,
-    max_waiting_ms: u64,
-) -> Result<bool> {
-    // Check the parameters.
+) -> Result<File> {
+    if resource == PsiResource::Cpu && kind == PsiKind::Full {
+        bail!("The cpu PSI resource doesn't support the full tracking type.");
+    }
     if stall_ms > window_ms {
         bail!("The stall time couldn't be larger than the time window.");
     }
-    if min_waiting_ms > max_waiting_ms {
-        bail!("The minimal waiting time couldn't be larger than the maximal waiting time.");
-    }
 
     let mut monitor_fd = OpenOptions::new()
         .read(true)
         .write(true)
         .custom_flags(libc::O_NONBLOCK)
-        .open("/proc/pressure/memory")
-        .context("Failed to open /proc/pressure/memory")?;
+        .open(resource.path())
+        .with_context(|| format!("Failed to open {}", resource.path()))?;
 
-    // The config shall be a C Style null terminated string.
-    let monitor_config = format!("some {} {}\0", stall_ms * 1000, window_ms * 1000);
+    // The config shall be a C style null terminated string.
+    let monitor_config = format!("{} {} {}\0", kind.as_str(), stall_ms * 1000, window_ms * 1000);
     let monitor_config_bytes = monitor_config.as_bytes();
     let monitor_config_size = monitor_config_bytes.len();
 
     match monitor_fd.write(monitor_config_bytes) {
         Ok(write_size) => {
-            // Writing monitor config string to /proc/pressure/memory doesn't trigger disk io, it
-            // should be fully written in 1 syscall.
+            // Writing monitor config string to /proc/pressure/<resource> doesn't trigger disk
+            // io, it should be fully written in 1 syscall.
             if write_size != monitor_config_size {
                 bail!(
-                    "Write psi memory file size: {} != monitor config size: {}",
+                    "Write psi {} file size: {} != monitor config size: {}",
+                    resource.path(),
                     write_size,
                     monitor_config_size
                 );
             }
         }
         Err(err) => {
-            bail!("Write psi memory file error: {}", err);
+            bail!("Write psi {} file error: {}", resource.path(), err);
         }
     }
 
+    Ok(monitor_fd)
+}
+
+/// Wait for a PSI monitor event that `resource`'s `kind` stall time exceeded a certain threshold
+/// in a recent time window. Returns Ok(true) if the PSI monitor event is triggered. Returns
+/// Ok(false) when waiting time exceeded `max_waiting_ms`.
+///
+/// # Arguments
+///
+/// * `resource` - Which `/proc/pressure/<resource>` file to monitor.
+/// * `kind` - Whether to trigger on `some` or `full` stalls.
+/// * `stall_ms` - Stall time in millisecond to trigger the PSI monitor event.
+/// * `window_ms` - Time window in millisecond to check the stall threshold.
+/// * `min_waiting_ms` - Minimal waiting time in millisecond. Used to prevent too frequent
+/// triggering.
+/// * `max_waiting_ms` - Maximal waiting time in millisecond. Used to prevent indefinite waiting.
+///
+/// PSI monitor documentation: https://docs.kernel.org/accounting/psi.html#monitoring-for-pressure-thresholds
+pub async fn wait_psi_monitor_event(
+    resource: PsiResource,
+    kind: PsiKind,
+    stall_ms: u64,
+    window_ms: u64,
+    min_waiting_ms: u64,
+    max_waiting_ms: u64,
+) -> Result<bool> {
+    if min_waiting_ms > max_waiting_ms {
+        bail!("The minimal waiting time couldn't be larger than the maximal waiting time.");
+    }
+
+    let monitor_fd = arm_psi_trigger(resource, kind, stall_ms, window_ms)?;
     let async_fd = create_epoll_pri(&monitor_fd).context("create_epoll_pri returns error")?;
 
     tokio::time::sleep(Duration::from_millis(min_waiting_ms)).await;
@@ -113,12 +222,120 @@ pub async fn wait_psi_monitor_memory_event(
     }
 }
 
+/// Identifies one trigger registered with a [`PsiMonitorSet`], in registration order.
+pub type TriggerId = usize;
+
+struct PsiTrigger {
+    // Kept alive only to hold the armed kernel trigger open; the set's epoll instance refers to
+    // it by raw fd, and the kernel drops the trigger as soon as the fd is closed.
+    _file: File,
+    resource: PsiResource,
+    kind: PsiKind,
+}
+
+/// Monitors several PSI triggers at once -- e.g. a gentle warning and an aggressive critical
+/// threshold on the same resource -- on a single epoll instance, so a caller can await all of
+/// them in one task instead of spawning one waiter per threshold.
+pub struct PsiMonitorSet {
+    epoll_fd: RawFd,
+    async_epoll_fd: AsyncFd<RawFd>,
+    triggers: Vec<PsiTrigger>,
+}
+
+impl PsiMonitorSet {
+    pub fn new() -> Result<Self> {
+        let epoll_fd =
+            cerr(unsafe { libc::epoll_create1(0) }).context("epoll_create1 returns error")?;
+        let async_epoll_fd = AsyncFd::new(epoll_fd).context("Failed to create AsyncFd")?;
+
+        Ok(PsiMonitorSet {
+            epoll_fd,
+            async_epoll_fd,
+            triggers: Vec::new(),
+        })
+    }
+
+    /// Arms a new trigger on its own fd -- the kernel associates one trigger per open file
+    /// descriptor -- and adds it to this set's epoll instance. Returns the [`TriggerId`] that
+    /// [`PsiMonitorSet::wait`] reports when this trigger fires.
+    pub fn add_trigger(
+        &mut self,
+        resource: PsiResource,
+        kind: PsiKind,
+        stall_ms: u64,
+        window_ms: u64,
+    ) -> Result<TriggerId> {
+        let file = arm_psi_trigger(resource, kind, stall_ms, window_ms)?;
+        let id: TriggerId = self.triggers.len();
+
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLPRI as u32,
+            u64: id as u64,
+        };
+        cerr(unsafe {
+            libc::epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_ADD, file.as_raw_fd(), &mut event)
+        })
+        .context("epoll_ctl returns error")?;
+
+        self.triggers.push(PsiTrigger {
+            _file: file,
+            resource,
+            kind,
+        });
+        Ok(id)
+    }
+
+    /// Waits for at least one registered trigger to fire, then returns the [`TriggerId`]s that
+    /// fired alongside every registered trigger's current pressure averages, in registration
+    /// order, so a caller can escalate by severity in one await.
+    pub async fn wait(&self) -> Result<(Vec<TriggerId>, Vec<PsiPressureLine>)> {
+        let mut guard = self
+            .async_epoll_fd
+            .readable()
+            .await
+            .context("epoll fd readiness wait failed")?;
+
+        let mut events =
+            vec![libc::epoll_event { events: 0, u64: 0 }; self.triggers.len().max(1)];
+        let ready_count = cerr(unsafe {
+            libc::epoll_wait(self.epoll_fd, events.as_mut_ptr(), events.len() as i32, 0)
+        })
+        .context("epoll_wait returns error")?;
+        guard.clear_ready();
+
+        let fired = events[..ready_count as usize]
+            .iter()
+            .map(|event| event.u64 as TriggerId)
+            .collect();
+
+        let pressures = self
+            .triggers
+            .iter()
+            .map(|trigger| read_psi_pressure(trigger.resource, trigger.kind))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok((fired, pressures))
+    }
+}
+
+impl Drop for PsiMonitorSet {
+    fn drop(&mut self) {
+        // `async_epoll_fd` wraps `epoll_fd` as a bare `RawFd`, which isn't
+        // closed on drop -- only the `AsyncFd` registration is torn down.
+        // Safe because `epoll_fd` was created by `epoll_create1` in `new`
+        // and isn't used again after this point.
+        unsafe {
+            libc::close(self.epoll_fd);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[tokio::test]
-    async fn test_wait_psi_monitor_memory_event() {
+    async fn test_wait_psi_monitor_event() {
         const MIN_WAITING_MS: u64 = 500;
         const MAX_WAITING_MS: u64 = 10000;
         const STALL_MS: u64 = 150;
@@ -128,7 +345,9 @@ mod tests {
         const WRONG_MIN_WAITING_MS: u64 = 10001;
 
         // It should return error when stall is larger than window.
-        assert!(wait_psi_monitor_memory_event(
+        assert!(wait_psi_monitor_event(
+            PsiResource::Memory,
+            PsiKind::Some,
             WRONG_STALL_MS,
             WINDOW_MS,
             MIN_WAITING_MS,
@@ -138,7 +357,9 @@ mod tests {
         .is_err());
 
         // It should return error when min waiting is larger than max waiting.
-        assert!(wait_psi_monitor_memory_event(
+        assert!(wait_psi_monitor_event(
+            PsiResource::Memory,
+            PsiKind::Some,
             STALL_MS,
             WINDOW_MS,
             WRONG_MIN_WAITING_MS,
@@ -146,5 +367,30 @@ mod tests {
         )
         .await
         .is_err());
+
+        // It should return error when requesting the full kind on the cpu resource.
+        assert!(wait_psi_monitor_event(
+            PsiResource::Cpu,
+            PsiKind::Full,
+            STALL_MS,
+            WINDOW_MS,
+            MIN_WAITING_MS,
+            MAX_WAITING_MS
+        )
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_pressure_line() {
+        let some_line = "some avg10=1.50 avg60=2.25 avg300=0.00 total=12345";
+        let parsed = parse_pressure_line(some_line, PsiKind::Some).unwrap();
+        assert_eq!(parsed.avg10, 1.50);
+        assert_eq!(parsed.avg60, 2.25);
+        assert_eq!(parsed.avg300, 0.00);
+        assert_eq!(parsed.total, 12345);
+
+        // Wrong kind for this line.
+        assert!(parse_pressure_line(some_line, PsiKind::Full).is_none());
     }
 }
@@ -0,0 +1,12 @@
+// Copyright 2019 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! An implementation of the 9P2000.L filesystem protocol server, used to
+//! export a host directory tree to VM guests over virtio-9p.
+
+pub mod protocol;
+pub mod server;
+
+pub use protocol::*;
+pub use server::Server;
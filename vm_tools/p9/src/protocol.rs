@@ -0,0 +1,357 @@
+// Copyright 2019 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Wire format definitions for the subset of the 9P2000.L protocol that this
+//! server implements.
+
+use std::io;
+use std::io::{Read, Write};
+use std::ops::{Deref, DerefMut};
+
+/// Types that can be read from and written to a 9P message body.
+pub trait WireFormat: Sized {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! wire_format_int {
+    ($ty:ty) => {
+        impl WireFormat for $ty {
+            fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                writer.write_all(&self.to_le_bytes())
+            }
+
+            fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+                let mut buf = [0u8; std::mem::size_of::<$ty>()];
+                reader.read_exact(&mut buf)?;
+                Ok(<$ty>::from_le_bytes(buf))
+            }
+        }
+    };
+}
+
+wire_format_int!(u8);
+wire_format_int!(u16);
+wire_format_int!(u32);
+wire_format_int!(u64);
+
+impl WireFormat for String {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let len = self.len() as u16;
+        len.encode(writer)?;
+        writer.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = u16::decode(reader)?;
+        let mut buf = vec![0u8; len as usize];
+        reader.read_exact(&mut buf)?;
+        String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl<T: WireFormat> WireFormat for Vec<T> {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (self.len() as u16).encode(writer)?;
+        for item in self {
+            item.encode(writer)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = u16::decode(reader)?;
+        let mut items = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            items.push(T::decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+/// A 9P data buffer: a `u32`-length-prefixed byte blob, used for
+/// read/write/readdir payloads.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Data(pub Vec<u8>);
+
+impl Deref for Data {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for Data {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl WireFormat for Data {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (self.0.len() as u32).encode(writer)?;
+        writer.write_all(&self.0)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = u32::decode(reader)?;
+        let mut buf = vec![0u8; len as usize];
+        reader.read_exact(&mut buf)?;
+        Ok(Data(buf))
+    }
+}
+
+/// Builds a plain-old-data struct together with a `WireFormat` impl that
+/// encodes/decodes its fields in declaration order. This stands in for the
+/// field-by-field boilerplate a `#[derive(WireFormat)]` macro would produce.
+macro_rules! p9_struct {
+    ($(#[$meta:meta])* $name:ident { $($field:ident: $ty:ty),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Default, Clone, PartialEq)]
+        pub struct $name {
+            $(pub $field: $ty),*
+        }
+
+        impl WireFormat for $name {
+            fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                $(self.$field.encode(writer)?;)*
+                Ok(())
+            }
+
+            fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+                Ok($name {
+                    $($field: WireFormat::decode(reader)?,)*
+                })
+            }
+        }
+    };
+}
+
+p9_struct!(Qid {
+    ty: u8,
+    version: u32,
+    path: u64,
+});
+
+p9_struct!(Dirent {
+    qid: Qid,
+    offset: u64,
+    ty: u8,
+    name: String,
+});
+
+p9_struct!(Tversion { msize: u32, version: String });
+p9_struct!(Rversion { msize: u32, version: String });
+
+p9_struct!(Tattach {
+    fid: u32,
+    afid: u32,
+    uname: String,
+    aname: String,
+    n_uname: u32,
+});
+p9_struct!(Rattach { qid: Qid });
+
+p9_struct!(Twalk {
+    fid: u32,
+    newfid: u32,
+    wnames: Vec<String>,
+});
+p9_struct!(Rwalk { wqids: Vec<Qid> });
+
+p9_struct!(Tgetattr { fid: u32, request_mask: u64 });
+p9_struct!(Rgetattr {
+    valid: u64,
+    qid: Qid,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u64,
+    rdev: u64,
+    size: u64,
+    blksize: u64,
+    blocks: u64,
+    atime_sec: u64,
+    atime_nsec: u64,
+    mtime_sec: u64,
+    mtime_nsec: u64,
+    ctime_sec: u64,
+    ctime_nsec: u64,
+    btime_sec: u64,
+    btime_nsec: u64,
+    gen: u64,
+    data_version: u64,
+});
+
+p9_struct!(Tsetattr {
+    fid: u32,
+    valid: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    atime_sec: u64,
+    atime_nsec: u64,
+    mtime_sec: u64,
+    mtime_nsec: u64,
+});
+p9_struct!(Rsetattr {});
+
+p9_struct!(Tlopen { fid: u32, flags: u32 });
+p9_struct!(Rlopen { qid: Qid, iounit: u32 });
+
+p9_struct!(Tlcreate {
+    fid: u32,
+    name: String,
+    flags: u32,
+    mode: u32,
+    gid: u32,
+});
+p9_struct!(Rlcreate { qid: Qid, iounit: u32 });
+
+p9_struct!(Tread { fid: u32, offset: u64, count: u32 });
+p9_struct!(Rread { data: Data });
+
+p9_struct!(Twrite { fid: u32, offset: u64, data: Data });
+p9_struct!(Rwrite { count: u32 });
+
+p9_struct!(Tfsync { fid: u32, datasync: u32 });
+p9_struct!(Rfsync {});
+
+p9_struct!(Treaddir { fid: u32, offset: u64, count: u32 });
+p9_struct!(Rreaddir { data: Data });
+
+p9_struct!(Tclunk { fid: u32 });
+p9_struct!(Rclunk {});
+
+p9_struct!(Tremove { fid: u32 });
+p9_struct!(Rremove {});
+
+p9_struct!(Tunlinkat {
+    dirfd: u32,
+    name: String,
+    flags: u32,
+});
+p9_struct!(Runlinkat {});
+
+p9_struct!(Trename {
+    fid: u32,
+    dfid: u32,
+    name: String,
+});
+p9_struct!(Rrename {});
+
+p9_struct!(Trenameat {
+    olddirfid: u32,
+    oldname: String,
+    newdirfid: u32,
+    newname: String,
+});
+p9_struct!(Rrenameat {});
+
+p9_struct!(Tmkdir {
+    dfid: u32,
+    name: String,
+    mode: u32,
+    gid: u32,
+});
+p9_struct!(Rmkdir { qid: Qid });
+
+p9_struct!(Tsymlink {
+    dfid: u32,
+    name: String,
+    symtgt: String,
+    gid: u32,
+});
+p9_struct!(Rsymlink { qid: Qid });
+
+p9_struct!(Treadlink { fid: u32 });
+p9_struct!(Rreadlink { target: String });
+
+p9_struct!(Tmknod {
+    dfid: u32,
+    name: String,
+    mode: u32,
+    major: u32,
+    minor: u32,
+    gid: u32,
+});
+p9_struct!(Rmknod { qid: Qid });
+
+p9_struct!(Txattrwalk {
+    fid: u32,
+    newfid: u32,
+    name: String,
+});
+p9_struct!(Rxattrwalk { size: u64 });
+
+p9_struct!(Txattrcreate {
+    fid: u32,
+    name: String,
+    attr_size: u64,
+    flags: u32,
+});
+p9_struct!(Rxattrcreate {});
+
+// Qid.ty bits (P9_QT*).
+pub const P9_QTDIR: u8 = 0x80;
+pub const P9_QTAPPEND: u8 = 0x40;
+pub const P9_QTEXCL: u8 = 0x20;
+pub const P9_QTMOUNT: u8 = 0x10;
+pub const P9_QTAUTH: u8 = 0x08;
+pub const P9_QTTMP: u8 = 0x04;
+pub const _P9_QTSYMLINK: u8 = 0x02;
+pub const P9_QTLINK: u8 = 0x01;
+pub const P9_QTFILE: u8 = 0x00;
+
+// Tgetattr.request_mask / Rgetattr.valid bits (P9_GETATTR_*).
+pub const P9_GETATTR_MODE: u64 = 0x0000_0001;
+pub const P9_GETATTR_NLINK: u64 = 0x0000_0002;
+pub const P9_GETATTR_UID: u64 = 0x0000_0004;
+pub const P9_GETATTR_GID: u64 = 0x0000_0008;
+pub const P9_GETATTR_RDEV: u64 = 0x0000_0010;
+pub const P9_GETATTR_ATIME: u64 = 0x0000_0020;
+pub const P9_GETATTR_MTIME: u64 = 0x0000_0040;
+pub const P9_GETATTR_CTIME: u64 = 0x0000_0080;
+pub const P9_GETATTR_INO: u64 = 0x0000_0100;
+pub const P9_GETATTR_SIZE: u64 = 0x0000_0200;
+pub const P9_GETATTR_BLOCKS: u64 = 0x0000_0400;
+pub const P9_GETATTR_BTIME: u64 = 0x0000_0800;
+pub const P9_GETATTR_GEN: u64 = 0x0000_1000;
+pub const P9_GETATTR_DATA_VERSION: u64 = 0x0000_2000;
+pub const P9_GETATTR_BASIC: u64 = P9_GETATTR_MODE
+    | P9_GETATTR_NLINK
+    | P9_GETATTR_UID
+    | P9_GETATTR_GID
+    | P9_GETATTR_RDEV
+    | P9_GETATTR_ATIME
+    | P9_GETATTR_MTIME
+    | P9_GETATTR_CTIME
+    | P9_GETATTR_INO
+    | P9_GETATTR_SIZE
+    | P9_GETATTR_BLOCKS;
+pub const _P9_GETATTR_ALL: u64 =
+    P9_GETATTR_BASIC | P9_GETATTR_BTIME | P9_GETATTR_GEN | P9_GETATTR_DATA_VERSION;
+
+// Tsetattr.valid bits (P9_SETATTR_*).
+pub const P9_SETATTR_MODE: u32 = 0x0000_0001;
+pub const P9_SETATTR_UID: u32 = 0x0000_0002;
+pub const P9_SETATTR_GID: u32 = 0x0000_0004;
+pub const P9_SETATTR_SIZE: u32 = 0x0000_0008;
+pub const P9_SETATTR_ATIME: u32 = 0x0000_0010;
+pub const P9_SETATTR_MTIME: u32 = 0x0000_0020;
+pub const _P9_SETATTR_CTIME: u32 = 0x0000_0040;
+pub const P9_SETATTR_ATIME_SET: u32 = 0x0000_0080;
+pub const P9_SETATTR_MTIME_SET: u32 = 0x0000_0100;
+
+// Tlopen/Tlcreate flags. The 9P2000.L protocol carries these as the host's
+// own `open(2)` flag values, so they map 1:1 onto `libc::O_*`.
+pub const _P9_RDONLY: u32 = libc::O_RDONLY as u32;
+pub const P9_WRONLY: u32 = libc::O_WRONLY as u32;
+pub const P9_RDWR: u32 = libc::O_RDWR as u32;
+pub const P9_CREATE: u32 = libc::O_CREAT as u32;
+pub const P9_EXCL: u32 = libc::O_EXCL as u32;
+pub const P9_TRUNC: u32 = libc::O_TRUNC as u32;
+pub const P9_APPEND: u32 = libc::O_APPEND as u32;
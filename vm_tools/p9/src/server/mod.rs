@@ -0,0 +1,1194 @@
+// Copyright 2019 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The 9P2000.L server state machine: a table of client fids, each backed by
+//! an open file descriptor rather than a resolved path, so that directory
+//! traversal and mutation go through `openat`/`mkdirat`/`unlinkat`/`renameat`
+//! relative to a parent directory fd instead of racily re-resolving an
+//! absolute path on every request.
+//!
+//! The two exceptions are the legacy `Tremove`/`Trename` ops: unlike their
+//! `Tunlinkat`/`Trenameat` "dotl" counterparts, their wire format carries
+//! only the target's own fid, with no parent directory fid to open relative
+//! to. `Server::remove`/`Server::rename` fall back to resolving through the
+//! fid's diagnostic `path` for this reason, and remain racy against a
+//! `Tsymlink` the same way the rest of this module no longer is. A real
+//! 9p2000.L client (e.g. Linux's `9p` driver) issues `Tunlinkat`/`Trenameat`
+//! instead once the server advertises `.L`, so this gap is only reachable by
+//! a client that deliberately falls back to the legacy ops.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+
+use nix::fcntl::{self, AtFlags, OFlag};
+use nix::sys::stat::{self, FileStat, Mode, SFlag};
+use nix::unistd;
+
+use crate::protocol::*;
+
+mod read_dir;
+
+use read_dir::ReadDir;
+
+#[cfg(test)]
+mod tests;
+
+/// Maps a single contiguous range of guest ids onto a contiguous range of
+/// host ids, `count` ids wide starting at `guest`/`host` respectively.
+#[derive(Debug, Clone, Copy)]
+pub struct IdMapping {
+    pub guest: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+impl IdMapping {
+    fn host_to_guest(&self, host: u32) -> Option<u32> {
+        host.checked_sub(self.host)
+            .filter(|offset| *offset < self.count)
+            .map(|offset| self.guest + offset)
+    }
+
+    fn guest_to_host(&self, guest: u32) -> Option<u32> {
+        guest
+            .checked_sub(self.guest)
+            .filter(|offset| *offset < self.count)
+            .map(|offset| self.host + offset)
+    }
+}
+
+/// A table of guest<->host id mappings for either uids or gids. An empty
+/// table means "no mapping configured", in which case ids pass through
+/// unchanged, matching the server's historical behavior of dealing in host
+/// ids directly.
+#[derive(Debug, Clone, Default)]
+pub struct IdMap {
+    mappings: Vec<IdMapping>,
+    /// Guest id reported for a host id that isn't covered by `mappings`,
+    /// once a mapping table is configured (e.g. the "nobody" uid/gid).
+    squash: Option<u32>,
+}
+
+impl IdMap {
+    pub fn new(mappings: Vec<IdMapping>, squash: Option<u32>) -> Self {
+        IdMap { mappings, squash }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    fn host_to_guest(&self, host: u32) -> u32 {
+        if self.mappings.is_empty() {
+            return host;
+        }
+        self.mappings
+            .iter()
+            .find_map(|m| m.host_to_guest(host))
+            .or(self.squash)
+            .unwrap_or(host)
+    }
+
+    /// Translates a guest id into a host id, returning `None` if the id has
+    /// no mapping and the table is non-empty (callers should treat this as
+    /// `EPERM`).
+    fn guest_to_host(&self, guest: u32) -> Option<u32> {
+        if self.mappings.is_empty() {
+            return Some(guest);
+        }
+        self.mappings.iter().find_map(|m| m.guest_to_host(guest))
+    }
+}
+
+pub type UidMap = IdMap;
+pub type GidMap = IdMap;
+
+/// A single client-visible handle. Starts out as an `O_PATH` descriptor
+/// produced by `walk`; `Tlopen`/`Tlcreate` replace it with a real handle
+/// opened with the requested access mode.
+struct Fid {
+    file: File,
+    // Retained purely as a diagnostic aid (and so `Tremove`/`Trename`, whose
+    // wire format carries no parent directory fid, have something to resolve
+    // through). Every other operation, including `Tlopen`/`Tlcreate`, goes
+    // through `openat`/`reopen` relative to a directory fd instead.
+    path: PathBuf,
+    filetype: libc::mode_t,
+    /// Set when `Txattrwalk`/`Txattrcreate` has turned this fid into an
+    /// xattr "view": `read`/`write` are redirected accordingly instead of
+    /// operating on `file` directly.
+    xattr: Option<XattrState>,
+}
+
+/// The xattr-specific state stashed on a [`Fid`] by `Txattrwalk` or
+/// `Txattrcreate`, redirecting the fid's subsequent `read`/`write` calls.
+enum XattrState {
+    /// Produced by `Txattrwalk`: the full value of the named attribute, or,
+    /// when the name was empty, the NUL-separated output of `flistxattr`.
+    /// Buffered up front so `read` can serve arbitrary offset/count slices
+    /// the way it would for a regular file.
+    Read(Vec<u8>),
+    /// Staged by `Txattrcreate`. `Twrite`s on this fid fill `buffer`
+    /// (pre-sized to `Txattrcreate.attr_size`) at their given offset instead
+    /// of writing to the file, since the protocol may split one attribute's
+    /// payload across several `Twrite`s; `written` tracks how many bytes
+    /// have landed so far. The buffer is committed in a single `fsetxattr`
+    /// call as soon as `written` reaches `buffer.len()`, or at `Tclunk` as a
+    /// fallback for a fid clunked before every byte arrived.
+    Write {
+        name: String,
+        flags: libc::c_int,
+        buffer: Vec<u8>,
+        written: usize,
+    },
+}
+
+/// The root-relative 9P server. Owns an `O_PATH` descriptor for the exported
+/// directory and a table of open fids, each rooted (transitively, via
+/// `openat`) under that descriptor.
+pub struct Server {
+    root: File,
+    root_path: PathBuf,
+    fids: BTreeMap<u32, Fid>,
+    msize: u32,
+    uid_map: UidMap,
+    gid_map: GidMap,
+}
+
+fn io_err_from_nix(e: nix::Error) -> io::Error {
+    io::Error::from_raw_os_error(e as i32)
+}
+
+fn qid_for(st: &FileStat) -> Qid {
+    let ty = match st.st_mode & libc::S_IFMT as libc::mode_t {
+        libc::S_IFDIR => P9_QTDIR,
+        libc::S_IFLNK => _P9_QTSYMLINK,
+        _ => P9_QTFILE,
+    };
+    Qid {
+        ty,
+        version: st.st_mtime as u32,
+        path: st.st_ino,
+    }
+}
+
+fn basic_rgetattr(st: &FileStat, uid_map: &IdMap, gid_map: &IdMap) -> Rgetattr {
+    Rgetattr {
+        valid: P9_GETATTR_BASIC,
+        qid: qid_for(st),
+        mode: st.st_mode,
+        uid: uid_map.host_to_guest(st.st_uid),
+        gid: gid_map.host_to_guest(st.st_gid),
+        nlink: st.st_nlink as u64,
+        rdev: st.st_rdev,
+        size: st.st_size as u64,
+        blksize: st.st_blksize as u64,
+        blocks: st.st_blocks as u64,
+        atime_sec: st.st_atime as u64,
+        atime_nsec: st.st_atime_nsec as u64,
+        mtime_sec: st.st_mtime as u64,
+        mtime_nsec: st.st_mtime_nsec as u64,
+        ctime_sec: st.st_ctime as u64,
+        ctime_nsec: st.st_ctime_nsec as u64,
+        btime_sec: 0,
+        btime_nsec: 0,
+        gen: 0,
+        data_version: 0,
+    }
+}
+
+fn basic_rgetattr_from_statx(buf: &libc::statx, uid_map: &IdMap, gid_map: &IdMap) -> Rgetattr {
+    let ty = match (buf.stx_mode as libc::mode_t) & libc::S_IFMT {
+        libc::S_IFDIR => P9_QTDIR,
+        libc::S_IFLNK => _P9_QTSYMLINK,
+        _ => P9_QTFILE,
+    };
+
+    Rgetattr {
+        valid: P9_GETATTR_BASIC,
+        qid: Qid {
+            ty,
+            version: buf.stx_mtime.tv_sec as u32,
+            path: buf.stx_ino,
+        },
+        mode: buf.stx_mode as u32,
+        uid: uid_map.host_to_guest(buf.stx_uid),
+        gid: gid_map.host_to_guest(buf.stx_gid),
+        nlink: buf.stx_nlink as u64,
+        rdev: libc::makedev(buf.stx_rdev_major, buf.stx_rdev_minor),
+        size: buf.stx_size,
+        blksize: buf.stx_blksize as u64,
+        blocks: buf.stx_blocks,
+        atime_sec: buf.stx_atime.tv_sec as u64,
+        atime_nsec: buf.stx_atime.tv_nsec as u64,
+        mtime_sec: buf.stx_mtime.tv_sec as u64,
+        mtime_nsec: buf.stx_mtime.tv_nsec as u64,
+        ctime_sec: buf.stx_ctime.tv_sec as u64,
+        ctime_nsec: buf.stx_ctime.tv_nsec as u64,
+        btime_sec: 0,
+        btime_nsec: 0,
+        gen: 0,
+        data_version: 0,
+    }
+}
+
+/// Joins `name` onto `path`, resolving `..` without ever escaping `root` and
+/// rejecting anything that isn't a single plain path component. Kept as a
+/// standalone helper: each [`Fid`] still tracks a diagnostic path (see
+/// above), and this is how that path is kept in sync as fids are walked.
+pub fn join_path(path: PathBuf, name: &str, root: &Path) -> io::Result<PathBuf> {
+    if name == ".." {
+        if path == root {
+            return Ok(path);
+        }
+        return Ok(path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| root.to_path_buf()));
+    }
+
+    if Path::new(name).components().count() != 1 {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    match Path::new(name).components().next() {
+        Some(Component::Normal(component)) => Ok(path.join(component)),
+        _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+    }
+}
+
+/// Reads the value of the extended attribute `name` on `fd`, using the
+/// size-probe convention (an initial zero-length call returns the required
+/// buffer length without copying any data).
+fn fgetxattr(fd: RawFd, name: &str) -> io::Result<Vec<u8>> {
+    let name = std::ffi::CString::new(name).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // Safe because `name` is NUL-terminated and a null buffer with a zero
+    // length is how `fgetxattr(2)` is documented to probe the value's size.
+    let needed = unsafe { libc::fgetxattr(fd, name.as_ptr(), std::ptr::null_mut(), 0) };
+    if needed < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buf = vec![0u8; needed as usize];
+    if !buf.is_empty() {
+        // Safe because `buf` is a valid, writable buffer of `buf.len()`
+        // bytes.
+        let n = unsafe {
+            libc::fgetxattr(fd, name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(n as usize);
+    }
+    Ok(buf)
+}
+
+/// Returns the NUL-separated list of extended attribute names set on `fd`,
+/// using the same size-probe convention as [`fgetxattr`].
+fn flistxattr(fd: RawFd) -> io::Result<Vec<u8>> {
+    // Safe because a null buffer with a zero length probes the list's size.
+    let needed = unsafe { libc::flistxattr(fd, std::ptr::null_mut(), 0) };
+    if needed < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buf = vec![0u8; needed as usize];
+    if !buf.is_empty() {
+        // Safe because `buf` is a valid, writable buffer of `buf.len()`
+        // bytes.
+        let n = unsafe { libc::flistxattr(fd, buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(n as usize);
+    }
+    Ok(buf)
+}
+
+/// Stores `value` as the extended attribute `name` on `fd`, honoring the
+/// `XATTR_CREATE`/`XATTR_REPLACE` semantics already baked into `flags`.
+fn fsetxattr(fd: RawFd, name: &str, value: &[u8], flags: libc::c_int) -> io::Result<()> {
+    let name = std::ffi::CString::new(name).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // Safe because `name` is NUL-terminated and `value` is a valid buffer of
+    // `value.len()` readable bytes.
+    let ret = unsafe {
+        libc::fsetxattr(
+            fd,
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            flags,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The complete set of `O_*` bits this server understands on `Tlopen`/
+/// `Tlcreate` flags (already expressed as raw `libc::O_*` values -- see the
+/// `P9_*` constants in `protocol.rs`). Anything else a client sets is
+/// dropped rather than passed through to `open(2)` unchecked.
+const MAPPED_FLAGS: libc::c_int =
+    libc::O_ACCMODE | libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC | libc::O_APPEND;
+
+/// Translates 9P open/create flags into the flags for a raw `open`/`openat`
+/// call, instead of deriving them via `OpenOptions`. `OpenOptions` rejects
+/// combinations -- `O_CREAT|O_RDONLY`, `O_APPEND|O_TRUNC`, and the like --
+/// that `open(2)` itself handles without complaint, and real clients rely on
+/// exactly that: Microsoft Edge, for instance, creates its download target
+/// as `O_RDONLY|O_CREAT` before reopening it `O_RDWR`.
+///
+/// `O_APPEND` implies the caller means to write, so a plain `O_RDONLY`
+/// access mode is widened to `O_RDWR` when it's set, matching what
+/// `OpenOptions::append(true)` did internally.
+fn translate_open_flags(flags: u32) -> libc::c_int {
+    let mut raw = flags as libc::c_int & MAPPED_FLAGS;
+    if raw & libc::O_APPEND != 0 && raw & libc::O_ACCMODE == libc::O_RDONLY {
+        raw = (raw & !libc::O_ACCMODE) | libc::O_RDWR;
+    }
+    raw
+}
+
+/// Fixed overhead of an `Rread`/`Rwrite` reply outside of its `Data`
+/// payload: the 9P message header (`size[4] type[1] tag[2]`) plus the
+/// `u32` length prefix `Data::encode` writes ahead of the bytes themselves.
+const IO_HEADER_SIZE: u32 = 4 + 1 + 2 + 4;
+
+/// Picks the `iounit` to hand back from `Rlopen`/`Rlcreate`: the largest
+/// payload that still fits a single `Rread`/`Rwrite` within the connection's
+/// negotiated `msize`, further clamped to the backing file's preferred I/O
+/// size so transfers line up with its blocks. Falls back to the
+/// msize-derived bound alone if `st_blksize` is unset or larger.
+fn negotiate_iounit(msize: u32, st: &FileStat) -> u32 {
+    let max = msize.saturating_sub(IO_HEADER_SIZE);
+    let blksize = st.st_blksize as u32;
+    if blksize > 0 && blksize < max {
+        blksize
+    } else {
+        max
+    }
+}
+
+impl Server {
+    /// Creates a server exporting the directory tree rooted at `root`.
+    ///
+    /// `uid_map`/`gid_map` translate ids between the guest and host
+    /// namespaces for multi-tenant or VM-sharing mounts; an empty map (the
+    /// `Default`) leaves ids untranslated.
+    pub fn new<P: AsRef<Path>>(root: P, uid_map: UidMap, gid_map: GidMap) -> Server {
+        let root_path = root.as_ref().to_path_buf();
+        let fd = fcntl::open(&root_path, OFlag::O_PATH | OFlag::O_DIRECTORY, Mode::empty())
+            .expect("failed to open p9 server root");
+
+        Server {
+            // Safe because `fd` was just created by `open` above and is not
+            // owned by anything else yet.
+            root: unsafe { File::from_raw_fd(fd) },
+            root_path,
+            fids: BTreeMap::new(),
+            msize: 0,
+            uid_map,
+            gid_map,
+        }
+    }
+
+    fn no_such_fid() -> io::Error {
+        io::Error::from(io::ErrorKind::NotFound)
+    }
+
+    /// Reopens the file backing `fid` with the given flags via the
+    /// `/proc/self/fd` magic-symlink trick. This lets operations that need a
+    /// "real" read/write handle (`fchmod`, `fchown`, `ftruncate`,
+    /// `futimens`, the xattr syscalls) work even when `fid` currently only
+    /// holds an `O_PATH` descriptor.
+    fn reopen(&self, fid_num: u32, flags: OFlag) -> io::Result<File> {
+        let fid = self.fids.get(&fid_num).ok_or_else(Server::no_such_fid)?;
+        let proc_path = format!("/proc/self/fd/{}", fid.file.as_raw_fd());
+        let fd = fcntl::open(proc_path.as_str(), flags, Mode::empty()).map_err(io_err_from_nix)?;
+        // Safe because `fd` was just created by `open` above.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    fn walk_one(&self, parent_fd: RawFd, name: &str) -> io::Result<(File, FileStat)> {
+        if name == ".." {
+            let root_st = stat::fstat(self.root.as_raw_fd()).map_err(io_err_from_nix)?;
+            let parent_st = stat::fstat(parent_fd).map_err(io_err_from_nix)?;
+            let at_root = parent_st.st_dev == root_st.st_dev && parent_st.st_ino == root_st.st_ino;
+
+            let fd = if at_root {
+                unistd::dup(parent_fd).map_err(io_err_from_nix)?
+            } else {
+                fcntl::openat(parent_fd, "..", OFlag::O_PATH | OFlag::O_NOFOLLOW, Mode::empty())
+                    .map_err(io_err_from_nix)?
+            };
+            // Safe because `fd` was just created above.
+            let file = unsafe { File::from_raw_fd(fd) };
+            let st = stat::fstat(file.as_raw_fd()).map_err(io_err_from_nix)?;
+            return Ok((file, st));
+        }
+
+        if name.is_empty() || name == "." || name.contains('/') {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let fd = fcntl::openat(parent_fd, name, OFlag::O_PATH | OFlag::O_NOFOLLOW, Mode::empty())
+            .map_err(io_err_from_nix)?;
+        // Safe because `fd` was just created above.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let st = stat::fstat(file.as_raw_fd()).map_err(io_err_from_nix)?;
+        Ok((file, st))
+    }
+
+    pub fn version(&mut self, tversion: &Tversion) -> io::Result<Rversion> {
+        // A fresh Tversion resets the whole connection, clunking every fid.
+        self.fids.clear();
+
+        if tversion.version != "9P2000.L" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported 9P version: {}", tversion.version),
+            ));
+        }
+
+        self.msize = tversion.msize;
+        Ok(Rversion {
+            msize: tversion.msize,
+            version: tversion.version.clone(),
+        })
+    }
+
+    pub fn attach(&mut self, tattach: &Tattach) -> io::Result<Rattach> {
+        if !self.uid_map.is_empty() && self.uid_map.guest_to_host(tattach.n_uname).is_none() {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        let fd = unistd::dup(self.root.as_raw_fd()).map_err(io_err_from_nix)?;
+        // Safe because `fd` was just created above.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let st = stat::fstat(file.as_raw_fd()).map_err(io_err_from_nix)?;
+        let filetype = st.st_mode & libc::S_IFMT as libc::mode_t;
+        let qid = qid_for(&st);
+
+        self.fids.insert(
+            tattach.fid,
+            Fid {
+                file,
+                path: self.root_path.clone(),
+                filetype,
+                xattr: None,
+            },
+        );
+
+        Ok(Rattach { qid })
+    }
+
+    pub fn walk(&mut self, twalk: &Twalk) -> io::Result<Rwalk> {
+        let start = self.fids.get(&twalk.fid).ok_or_else(Server::no_such_fid)?;
+        let start_fd = start.file.as_raw_fd();
+        let start_path = start.path.clone();
+
+        if twalk.wnames.is_empty() {
+            let dup_fd = unistd::dup(start_fd).map_err(io_err_from_nix)?;
+            // Safe because `dup_fd` was just created above.
+            let file = unsafe { File::from_raw_fd(dup_fd) };
+            let filetype = start.filetype;
+            self.fids.insert(
+                twalk.newfid,
+                Fid {
+                    file,
+                    path: start_path,
+                    filetype,
+                    xattr: None,
+                },
+            );
+            return Ok(Rwalk { wqids: Vec::new() });
+        }
+
+        let mut current_fd = start_fd;
+        let mut owned = None;
+        let mut path = start_path;
+        let mut filetype = start.filetype;
+        let mut wqids = Vec::with_capacity(twalk.wnames.len());
+
+        for name in &twalk.wnames {
+            let (file, st) = self.walk_one(current_fd, name)?;
+            path = join_path(path, name, &self.root_path)?;
+            filetype = st.st_mode & libc::S_IFMT as libc::mode_t;
+            wqids.push(qid_for(&st));
+            current_fd = file.as_raw_fd();
+            owned = Some(file);
+        }
+
+        if let Some(file) = owned {
+            self.fids.insert(
+                twalk.newfid,
+                Fid {
+                    file,
+                    path,
+                    filetype,
+                    xattr: None,
+                },
+            );
+        }
+
+        Ok(Rwalk { wqids })
+    }
+
+    pub fn get_attr(&mut self, tgetattr: &Tgetattr) -> io::Result<Rgetattr> {
+        let fid = self.fids.get(&tgetattr.fid).ok_or_else(Server::no_such_fid)?;
+
+        // GEN and DATA_VERSION have no portable Linux equivalent (they'd
+        // need a filesystem-specific ioctl), so the only extended field
+        // worth a statx call is BTIME; fall back to the cheaper fstat
+        // path otherwise, as before.
+        if tgetattr.request_mask & P9_GETATTR_BTIME == 0 {
+            let st = stat::fstat(fid.file.as_raw_fd()).map_err(io_err_from_nix)?;
+            return Ok(basic_rgetattr(&st, &self.uid_map, &self.gid_map));
+        }
+
+        let mut buf: libc::statx = unsafe { std::mem::zeroed() };
+        let empty_path = std::ffi::CString::new("").expect("empty CString");
+        // Safe because `fid.file` is a valid, open file descriptor, `buf` is
+        // a valid, writable `statx` buffer, and the empty pathname combined
+        // with `AT_EMPTY_PATH` tells the kernel to stat the fd itself.
+        let ret = unsafe {
+            libc::statx(
+                fid.file.as_raw_fd(),
+                empty_path.as_ptr(),
+                libc::AT_EMPTY_PATH,
+                (libc::STATX_BASIC_STATS | libc::STATX_BTIME) as libc::c_uint,
+                &mut buf,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut rgetattr = basic_rgetattr_from_statx(&buf, &self.uid_map, &self.gid_map);
+        if buf.stx_mask & libc::STATX_BTIME != 0 {
+            rgetattr.valid |= P9_GETATTR_BTIME;
+            rgetattr.btime_sec = buf.stx_btime.tv_sec as u64;
+            rgetattr.btime_nsec = buf.stx_btime.tv_nsec as u64;
+        }
+
+        Ok(rgetattr)
+    }
+
+    pub fn set_attr(&mut self, tsetattr: &Tsetattr) -> io::Result<Rsetattr> {
+        if !self.fids.contains_key(&tsetattr.fid) {
+            return Err(Server::no_such_fid());
+        }
+
+        if tsetattr.valid & P9_SETATTR_MODE != 0 {
+            let reopened = self.reopen(tsetattr.fid, OFlag::O_RDONLY)?;
+            stat::fchmod(
+                reopened.as_raw_fd(),
+                Mode::from_bits_truncate(tsetattr.mode as libc::mode_t),
+            )
+            .map_err(io_err_from_nix)?;
+        }
+
+        if tsetattr.valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            let uid = if tsetattr.valid & P9_SETATTR_UID != 0 {
+                let host = self
+                    .uid_map
+                    .guest_to_host(tsetattr.uid)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EPERM))?;
+                Some(unistd::Uid::from_raw(host))
+            } else {
+                None
+            };
+            let gid = if tsetattr.valid & P9_SETATTR_GID != 0 {
+                let host = self
+                    .gid_map
+                    .guest_to_host(tsetattr.gid)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EPERM))?;
+                Some(unistd::Gid::from_raw(host))
+            } else {
+                None
+            };
+            let reopened = self.reopen(tsetattr.fid, OFlag::O_RDONLY)?;
+            unistd::fchown(reopened.as_raw_fd(), uid, gid).map_err(io_err_from_nix)?;
+        }
+
+        if tsetattr.valid & P9_SETATTR_SIZE != 0 {
+            let reopened = self.reopen(tsetattr.fid, OFlag::O_WRONLY)?;
+            unistd::ftruncate(reopened.as_raw_fd(), tsetattr.size as libc::off_t)
+                .map_err(io_err_from_nix)?;
+        }
+
+        if tsetattr.valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let reopened = self.reopen(tsetattr.fid, OFlag::O_RDONLY)?;
+            set_times(reopened.as_raw_fd(), tsetattr)?;
+        }
+
+        Ok(Rsetattr::default())
+    }
+
+    /// Clones `fid` into `newfid`, turning the clone into a read-only view
+    /// over an extended attribute: its value if `name` is non-empty, or the
+    /// NUL-separated attribute list if `name` is empty. `Rxattrwalk.size`
+    /// reports the view's full length up front.
+    pub fn xattr_walk(&mut self, txattrwalk: &Txattrwalk) -> io::Result<Rxattrwalk> {
+        let fid = self.fids.get(&txattrwalk.fid).ok_or_else(Server::no_such_fid)?;
+        let dup_fd = unistd::dup(fid.file.as_raw_fd()).map_err(io_err_from_nix)?;
+        // Safe because `dup_fd` was just created above.
+        let file = unsafe { File::from_raw_fd(dup_fd) };
+        let path = fid.path.clone();
+        let filetype = fid.filetype;
+
+        let real = self.reopen(txattrwalk.fid, OFlag::O_RDONLY)?;
+        let buf = if txattrwalk.name.is_empty() {
+            flistxattr(real.as_raw_fd())?
+        } else {
+            fgetxattr(real.as_raw_fd(), &txattrwalk.name)?
+        };
+        let size = buf.len() as u64;
+
+        self.fids.insert(
+            txattrwalk.newfid,
+            Fid {
+                file,
+                path,
+                filetype,
+                xattr: Some(XattrState::Read(buf)),
+            },
+        );
+
+        Ok(Rxattrwalk { size })
+    }
+
+    /// Stages `fid` so that the next `Twrite` on it stores its payload as
+    /// the extended attribute `name` instead of writing to the file.
+    pub fn xattr_create(&mut self, txattrcreate: &Txattrcreate) -> io::Result<Rxattrcreate> {
+        let flags = match txattrcreate.flags {
+            0 => 0,
+            f if f == libc::XATTR_CREATE as u32 => libc::XATTR_CREATE,
+            f if f == libc::XATTR_REPLACE as u32 => libc::XATTR_REPLACE,
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+
+        let fid = self
+            .fids
+            .get_mut(&txattrcreate.fid)
+            .ok_or_else(Server::no_such_fid)?;
+        fid.xattr = Some(XattrState::Write {
+            name: txattrcreate.name.clone(),
+            flags,
+            buffer: vec![0u8; txattrcreate.attr_size as usize],
+            written: 0,
+        });
+
+        Ok(Rxattrcreate::default())
+    }
+
+    pub fn lopen(&mut self, tlopen: &Tlopen) -> io::Result<Rlopen> {
+        // Reopens the exact inode `tlopen.fid` already points at via
+        // `/proc/self/fd`, rather than re-resolving `fid.path` with a plain
+        // `open()`: the latter would race a `Tsymlink` that swaps a path
+        // component for a symlink between the fid's walk and this open.
+        let flags = translate_open_flags(tlopen.flags);
+        let file = self.reopen(tlopen.fid, OFlag::from_bits_truncate(flags))?;
+        let st = stat::fstat(file.as_raw_fd()).map_err(io_err_from_nix)?;
+        let qid = qid_for(&st);
+
+        let iounit = negotiate_iounit(self.msize, &st);
+
+        let fid = self.fids.get_mut(&tlopen.fid).expect("fid disappeared during lopen");
+        fid.filetype = st.st_mode & libc::S_IFMT as libc::mode_t;
+        fid.file = file;
+
+        Ok(Rlopen { qid, iounit })
+    }
+
+    pub fn lcreate(&mut self, tlcreate: &Tlcreate) -> io::Result<Rlcreate> {
+        let dir_path = self
+            .fids
+            .get(&tlcreate.fid)
+            .ok_or_else(Server::no_such_fid)?
+            .path
+            .clone();
+        let dir_fd = self
+            .fids
+            .get(&tlcreate.fid)
+            .ok_or_else(Server::no_such_fid)?
+            .file
+            .as_raw_fd();
+
+        // Resolved before the file is created, so an unmapped gid is
+        // rejected without leaving a new file behind.
+        let host_gid = if self.gid_map.is_empty() {
+            None
+        } else {
+            Some(
+                self.gid_map
+                    .guest_to_host(tlcreate.gid)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EPERM))?,
+            )
+        };
+
+        // Tlcreate always creates a brand new file, regardless of what the
+        // client set in `flags`. Resolved via `openat(dir_fd, name, ...)`
+        // relative to the parent fid's own descriptor rather than a plain
+        // `open()` on a joined path, so a `Tsymlink` swapping `name` for a
+        // symlink between the fid's walk and this create can't be raced.
+        let flags = translate_open_flags(tlcreate.flags) | libc::O_CREAT | libc::O_EXCL;
+        let fd = fcntl::openat(
+            dir_fd,
+            tlcreate.name.as_str(),
+            OFlag::from_bits_truncate(flags) | OFlag::O_NOFOLLOW,
+            Mode::from_bits_truncate(0o666),
+        )
+        .map_err(io_err_from_nix)?;
+        // Safe because `fd` was just created above.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        stat::fchmod(
+            file.as_raw_fd(),
+            Mode::from_bits_truncate(tlcreate.mode as libc::mode_t),
+        )
+        .map_err(io_err_from_nix)?;
+
+        if let Some(host_gid) = host_gid {
+            unistd::fchown(file.as_raw_fd(), None, Some(unistd::Gid::from_raw(host_gid)))
+                .map_err(io_err_from_nix)?;
+        }
+
+        let st = stat::fstat(file.as_raw_fd()).map_err(io_err_from_nix)?;
+        let qid = qid_for(&st);
+
+        let iounit = negotiate_iounit(self.msize, &st);
+
+        // Diagnostic only; not used to resolve the file itself.
+        let file_path = join_path(dir_path, &tlcreate.name, &self.root_path)?;
+
+        let fid = self
+            .fids
+            .get_mut(&tlcreate.fid)
+            .expect("fid disappeared during lcreate");
+        fid.path = file_path;
+        fid.filetype = st.st_mode & libc::S_IFMT as libc::mode_t;
+        fid.file = file;
+
+        Ok(Rlcreate { qid, iounit })
+    }
+
+    pub fn read(&mut self, tread: &Tread) -> io::Result<Rread> {
+        let fid = self.fids.get(&tread.fid).ok_or_else(Server::no_such_fid)?;
+
+        if let Some(XattrState::Read(buf)) = &fid.xattr {
+            let offset = tread.offset as usize;
+            let data = if offset >= buf.len() {
+                Vec::new()
+            } else {
+                let end = buf.len().min(offset + tread.count as usize);
+                buf[offset..end].to_vec()
+            };
+            return Ok(Rread { data: Data(data) });
+        }
+
+        let mut buf = vec![0u8; tread.count as usize];
+        let n = fid.file.read_at(&mut buf, tread.offset)?;
+        buf.truncate(n);
+        Ok(Rread { data: Data(buf) })
+    }
+
+    pub fn write(&mut self, twrite: &Twrite) -> io::Result<Rwrite> {
+        // Scoped so the mutable borrow of `fid.xattr` ends before a
+        // completed buffer is flushed below via `self.flush_xattr_write`.
+        let xattr_complete = {
+            let fid = self.fids.get_mut(&twrite.fid).ok_or_else(Server::no_such_fid)?;
+            match &mut fid.xattr {
+                Some(XattrState::Write {
+                    buffer, written, ..
+                }) => {
+                    let offset = twrite.offset as usize;
+                    let end = offset
+                        .checked_add(twrite.data.len())
+                        .filter(|&end| end <= buffer.len())
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+                    buffer[offset..end].copy_from_slice(&twrite.data);
+                    *written += twrite.data.len();
+                    Some(*written >= buffer.len())
+                }
+                _ => None,
+            }
+        };
+
+        if let Some(complete) = xattr_complete {
+            if complete {
+                self.flush_xattr_write(twrite.fid)?;
+            }
+            return Ok(Rwrite {
+                count: twrite.data.len() as u32,
+            });
+        }
+
+        let fid = self.fids.get(&twrite.fid).ok_or_else(Server::no_such_fid)?;
+        let n = fid.file.write_at(&twrite.data, twrite.offset)?;
+        Ok(Rwrite { count: n as u32 })
+    }
+
+    /// Commits a fid's staged [`XattrState::Write`] buffer via a single
+    /// `fsetxattr`, if one is staged. Called once the buffer fills (from
+    /// `write`) and again from `clunk` as a fallback for a fid clunked
+    /// before every byte arrived; a no-op the second time since the state
+    /// was already taken.
+    fn flush_xattr_write(&mut self, fid_num: u32) -> io::Result<()> {
+        let write = self.fids.get_mut(&fid_num).and_then(|fid| fid.xattr.take());
+        let (name, flags, buffer) = match write {
+            Some(XattrState::Write {
+                name,
+                flags,
+                buffer,
+                ..
+            }) => (name, flags, buffer),
+            _ => return Ok(()),
+        };
+
+        let real = self.reopen(fid_num, OFlag::O_RDONLY)?;
+        fsetxattr(real.as_raw_fd(), &name, &buffer, flags)
+    }
+
+    pub fn fsync(&mut self, tfsync: &Tfsync) -> io::Result<Rfsync> {
+        let fid = self.fids.get(&tfsync.fid).ok_or_else(Server::no_such_fid)?;
+        if tfsync.datasync != 0 {
+            fid.file.sync_data()?;
+        } else {
+            fid.file.sync_all()?;
+        }
+        Ok(Rfsync::default())
+    }
+
+    pub fn readdir(&mut self, treaddir: &Treaddir) -> io::Result<Rreaddir> {
+        let real_dir = self.reopen(treaddir.fid, OFlag::O_RDONLY | OFlag::O_DIRECTORY)?;
+        let mut stream = ReadDir::new(real_dir.as_raw_fd());
+        if treaddir.offset != 0 {
+            stream.seek(treaddir.offset)?;
+        }
+
+        let mut buf = Vec::new();
+        let mut used = 0u64;
+
+        while let Some(entry) = stream.next()? {
+            // getdents64 doesn't hand back enough information (notably
+            // mtime) to build a full qid, so look the entry up by name
+            // relative to the directory fd to fill one in.
+            let st = stat::fstatat(
+                real_dir.as_raw_fd(),
+                entry.name.as_str(),
+                AtFlags::AT_SYMLINK_NOFOLLOW,
+            )
+            .map_err(io_err_from_nix)?;
+
+            let dirent = Dirent {
+                qid: qid_for(&st),
+                offset: entry.off as u64,
+                ty: entry.ty,
+                name: entry.name,
+            };
+
+            let mut encoded = Vec::new();
+            dirent.encode(&mut encoded)?;
+            if used + encoded.len() as u64 > treaddir.count as u64 {
+                break;
+            }
+            used += encoded.len() as u64;
+            buf.extend_from_slice(&encoded);
+        }
+
+        Ok(Rreaddir { data: Data(buf) })
+    }
+
+    pub fn clunk(&mut self, tclunk: &Tclunk) -> io::Result<Rclunk> {
+        self.flush_xattr_write(tclunk.fid)?;
+        self.fids.remove(&tclunk.fid).ok_or_else(Server::no_such_fid)?;
+        Ok(Rclunk::default())
+    }
+
+    pub fn mkdir(&mut self, tmkdir: &Tmkdir) -> io::Result<Rmkdir> {
+        let dir_fd = self
+            .fids
+            .get(&tmkdir.dfid)
+            .ok_or_else(Server::no_such_fid)?
+            .file
+            .as_raw_fd();
+
+        stat::mkdirat(
+            Some(dir_fd),
+            tmkdir.name.as_str(),
+            Mode::from_bits_truncate(tmkdir.mode as libc::mode_t),
+        )
+        .map_err(io_err_from_nix)?;
+
+        let new_fd = fcntl::openat(dir_fd, tmkdir.name.as_str(), OFlag::O_PATH | OFlag::O_NOFOLLOW, Mode::empty())
+            .map_err(io_err_from_nix)?;
+        // Safe because `new_fd` was just created above.
+        let new_dir = unsafe { File::from_raw_fd(new_fd) };
+
+        if !self.gid_map.is_empty() {
+            let host_gid = self
+                .gid_map
+                .guest_to_host(tmkdir.gid)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EPERM))?;
+            let proc_path = format!("/proc/self/fd/{}", new_dir.as_raw_fd());
+            let reopened =
+                fcntl::open(proc_path.as_str(), OFlag::O_RDONLY, Mode::empty()).map_err(io_err_from_nix)?;
+            let result = unistd::fchown(reopened, None, Some(unistd::Gid::from_raw(host_gid)));
+            let _ = unistd::close(reopened);
+            result.map_err(io_err_from_nix)?;
+        }
+
+        let st = stat::fstat(new_dir.as_raw_fd()).map_err(io_err_from_nix)?;
+
+        Ok(Rmkdir { qid: qid_for(&st) })
+    }
+
+    /// Creates a symlink named `name` under `dfid` pointing at `symtgt`.
+    /// Unlike `Tlcreate`/`Tmkdir`, this doesn't give the new entry its own
+    /// fid; `dfid` keeps pointing at the containing directory.
+    pub fn symlink(&mut self, tsymlink: &Tsymlink) -> io::Result<Rsymlink> {
+        let dir_fd = self
+            .fids
+            .get(&tsymlink.dfid)
+            .ok_or_else(Server::no_such_fid)?
+            .file
+            .as_raw_fd();
+
+        unistd::symlinkat(
+            tsymlink.symtgt.as_str(),
+            Some(dir_fd),
+            tsymlink.name.as_str(),
+        )
+        .map_err(io_err_from_nix)?;
+
+        if !self.gid_map.is_empty() {
+            let host_gid = self
+                .gid_map
+                .guest_to_host(tsymlink.gid)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EPERM))?;
+            unistd::fchownat(
+                Some(dir_fd),
+                tsymlink.name.as_str(),
+                None,
+                Some(unistd::Gid::from_raw(host_gid)),
+                unistd::FchownatFlags::NoFollowSymlink,
+            )
+            .map_err(io_err_from_nix)?;
+        }
+
+        // A symlink's own qid must reflect the link itself, not its target,
+        // so this has to stat the link with AT_SYMLINK_NOFOLLOW rather than
+        // following it through `fstat` on a regular open.
+        let st = stat::fstatat(dir_fd, tsymlink.name.as_str(), AtFlags::AT_SYMLINK_NOFOLLOW)
+            .map_err(io_err_from_nix)?;
+
+        Ok(Rsymlink { qid: qid_for(&st) })
+    }
+
+    /// Returns the target of the symlink `fid` is attached to.
+    pub fn readlink(&mut self, treadlink: &Treadlink) -> io::Result<Rreadlink> {
+        let fid = self.fids.get(&treadlink.fid).ok_or_else(Server::no_such_fid)?;
+
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let empty_path = std::ffi::CString::new("").expect("empty CString");
+        // Safe because `fid.file` is a valid, open (`O_PATH`) file descriptor
+        // to the symlink itself, `buf` is a valid, writable buffer of
+        // `buf.len()` bytes, and the empty pathname combined with
+        // `AT_EMPTY_PATH` tells the kernel to read the link at the fd
+        // itself rather than re-resolving `fid.path`, which could have been
+        // swapped out from under this fid by a racing `Tsymlink`/rename.
+        let n = unsafe {
+            libc::readlinkat(
+                fid.file.as_raw_fd(),
+                empty_path.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(n as usize);
+
+        let target =
+            String::from_utf8(buf).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+
+        Ok(Rreadlink { target })
+    }
+
+    /// Creates a device/pipe/socket node named `name` under `dfid`. As with
+    /// `symlink`, the new node doesn't get a fid of its own.
+    pub fn mknod(&mut self, tmknod: &Tmknod) -> io::Result<Rmknod> {
+        let dir_fd = self
+            .fids
+            .get(&tmknod.dfid)
+            .ok_or_else(Server::no_such_fid)?
+            .file
+            .as_raw_fd();
+
+        let kind = SFlag::from_bits_truncate((tmknod.mode as libc::mode_t) & libc::S_IFMT);
+        let perm = Mode::from_bits_truncate((tmknod.mode as libc::mode_t) & !libc::S_IFMT);
+        let dev = libc::makedev(tmknod.major, tmknod.minor);
+
+        stat::mknodat(Some(dir_fd), tmknod.name.as_str(), kind, perm, dev).map_err(io_err_from_nix)?;
+
+        if !self.gid_map.is_empty() {
+            let host_gid = self
+                .gid_map
+                .guest_to_host(tmknod.gid)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EPERM))?;
+            unistd::fchownat(
+                Some(dir_fd),
+                tmknod.name.as_str(),
+                None,
+                Some(unistd::Gid::from_raw(host_gid)),
+                unistd::FchownatFlags::NoFollowSymlink,
+            )
+            .map_err(io_err_from_nix)?;
+        }
+
+        let st = stat::fstatat(dir_fd, tmknod.name.as_str(), AtFlags::AT_SYMLINK_NOFOLLOW)
+            .map_err(io_err_from_nix)?;
+
+        Ok(Rmknod { qid: qid_for(&st) })
+    }
+
+    /// Legacy removal op: prefer `Tunlinkat`/`Server::unlink_at` where
+    /// available. See the module doc comment for why this still resolves
+    /// through `fid.path` rather than an `unlinkat` relative to a parent
+    /// directory fd.
+    pub fn remove(&mut self, tremove: &Tremove) -> io::Result<Rremove> {
+        let fid = self.fids.remove(&tremove.fid).ok_or_else(Server::no_such_fid)?;
+        if fid.filetype == libc::S_IFDIR as libc::mode_t {
+            fs::remove_dir(&fid.path)?;
+        } else {
+            fs::remove_file(&fid.path)?;
+        }
+        Ok(Rremove::default())
+    }
+
+    pub fn unlink_at(&mut self, tunlinkat: &Tunlinkat) -> io::Result<Runlinkat> {
+        let dir_fd = self
+            .fids
+            .get(&tunlinkat.dirfd)
+            .ok_or_else(Server::no_such_fid)?
+            .file
+            .as_raw_fd();
+
+        let flag = if tunlinkat.flags & libc::AT_REMOVEDIR as u32 != 0 {
+            unistd::UnlinkatFlags::RemoveDir
+        } else {
+            unistd::UnlinkatFlags::NoRemoveDir
+        };
+
+        unistd::unlinkat(Some(dir_fd), tunlinkat.name.as_str(), flag).map_err(io_err_from_nix)?;
+        Ok(Runlinkat::default())
+    }
+
+    /// Legacy rename op: prefer `Trenameat`/`Server::rename_at` where
+    /// available. See the module doc comment for why this still resolves
+    /// through `fid.path` rather than a `renameat` relative to parent
+    /// directory fds.
+    pub fn rename(&mut self, trename: &Trename) -> io::Result<Rrename> {
+        let old_path = self
+            .fids
+            .get(&trename.fid)
+            .ok_or_else(Server::no_such_fid)?
+            .path
+            .clone();
+        let new_dir_path = self
+            .fids
+            .get(&trename.dfid)
+            .ok_or_else(Server::no_such_fid)?
+            .path
+            .clone();
+        let new_path = new_dir_path.join(&trename.name);
+
+        fs::rename(&old_path, &new_path)?;
+
+        let fid = self
+            .fids
+            .get_mut(&trename.fid)
+            .expect("fid disappeared during rename");
+        fid.path = new_path;
+
+        Ok(Rrename::default())
+    }
+
+    pub fn rename_at(&mut self, trenameat: &Trenameat) -> io::Result<Rrenameat> {
+        let old_dir_fd = self
+            .fids
+            .get(&trenameat.olddirfid)
+            .ok_or_else(Server::no_such_fid)?
+            .file
+            .as_raw_fd();
+        let new_dir_fd = self
+            .fids
+            .get(&trenameat.newdirfid)
+            .ok_or_else(Server::no_such_fid)?
+            .file
+            .as_raw_fd();
+
+        fcntl::renameat(
+            Some(old_dir_fd),
+            trenameat.oldname.as_str(),
+            Some(new_dir_fd),
+            trenameat.newname.as_str(),
+        )
+        .map_err(io_err_from_nix)?;
+
+        Ok(Rrenameat::default())
+    }
+}
+
+fn set_times(fd: RawFd, tsetattr: &Tsetattr) -> io::Result<()> {
+    let now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: libc::UTIME_NOW,
+    };
+    let omit = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: libc::UTIME_OMIT,
+    };
+
+    let atime = if tsetattr.valid & P9_SETATTR_ATIME == 0 {
+        omit
+    } else if tsetattr.valid & P9_SETATTR_ATIME_SET != 0 {
+        libc::timespec {
+            tv_sec: tsetattr.atime_sec as libc::time_t,
+            tv_nsec: tsetattr.atime_nsec as i64,
+        }
+    } else {
+        now
+    };
+
+    let mtime = if tsetattr.valid & P9_SETATTR_MTIME == 0 {
+        omit
+    } else if tsetattr.valid & P9_SETATTR_MTIME_SET != 0 {
+        libc::timespec {
+            tv_sec: tsetattr.mtime_sec as libc::time_t,
+            tv_nsec: tsetattr.mtime_nsec as i64,
+        }
+    } else {
+        now
+    };
+
+    let times = [atime, mtime];
+    // Safe because `fd` is a valid, open file descriptor owned by this
+    // process and `times` points to a 2-element array as futimens(2) expects.
+    let ret = unsafe { libc::futimens(fd, times.as_ptr()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
@@ -0,0 +1,113 @@
+// Copyright 2019 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A streaming wrapper around the `getdents64` syscall.
+//!
+//! Unlike `std::fs::read_dir`, this reads directly from the kernel's
+//! `linux_dirent64` records, so `.` and `..` come back like any other entry
+//! and the opaque `d_off` cookie is available for precise resumption of a
+//! directory stream that's larger than one 9P `msize`.
+
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::io::RawFd;
+
+/// Large enough to amortize the syscall cost without wasting much memory
+/// when only a handful of entries are requested.
+const BUF_SIZE: usize = 8192;
+
+/// One entry read back from `getdents64`.
+pub struct DirEntry {
+    pub ino: u64,
+    /// The kernel's opaque stream-position cookie for this entry. Passing
+    /// this back as the next `Treaddir.offset` resumes exactly here.
+    pub off: i64,
+    pub ty: u8,
+    pub name: String,
+}
+
+/// Streams directory entries out of an open directory file descriptor.
+pub struct ReadDir {
+    fd: RawFd,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+}
+
+impl ReadDir {
+    /// Wraps `fd`, which must be open on a directory and valid for the
+    /// lifetime of this `ReadDir`. Ownership of `fd` stays with the caller.
+    pub fn new(fd: RawFd) -> ReadDir {
+        ReadDir {
+            fd,
+            buf: vec![0u8; BUF_SIZE],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// Repositions the underlying directory stream to `offset`, discarding
+    /// any buffered entries. `offset` must be either `0` (rewind to the
+    /// start) or a cookie previously returned as `DirEntry::off`.
+    pub fn seek(&mut self, offset: u64) -> io::Result<()> {
+        // Safe because `self.fd` is a valid, open directory descriptor and
+        // lseek has no effect on memory safety.
+        let ret = unsafe { libc::lseek64(self.fd, offset as libc::off64_t, libc::SEEK_SET) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.pos = 0;
+        self.len = 0;
+        Ok(())
+    }
+
+    fn fill_buf(&mut self) -> io::Result<bool> {
+        // Safe because `self.buf` is a valid buffer of `self.buf.len()`
+        // writable bytes and `self.fd` is a valid, open directory
+        // descriptor.
+        let n = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                self.fd,
+                self.buf.as_mut_ptr(),
+                self.buf.len(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.pos = 0;
+        self.len = n as usize;
+        Ok(self.len > 0)
+    }
+
+    /// Returns the next directory entry, refilling the internal buffer with
+    /// another `getdents64` call if necessary. Returns `Ok(None)` once the
+    /// stream is exhausted.
+    pub fn next(&mut self) -> io::Result<Option<DirEntry>> {
+        if self.pos >= self.len && !self.fill_buf()? {
+            return Ok(None);
+        }
+
+        // Safe because `fill_buf` only ever reports `self.len` bytes that
+        // the kernel actually wrote, and those bytes are laid out exactly as
+        // `struct linux_dirent64`: a u64 inode, an i64 offset cookie, a u16
+        // record length, a u8 type, then a NUL-terminated name, in that
+        // order with no padding.
+        unsafe {
+            let record = self.buf.as_ptr().add(self.pos);
+            let ino = (record as *const u64).read_unaligned();
+            let off = (record.add(8) as *const i64).read_unaligned();
+            let reclen = (record.add(16) as *const u16).read_unaligned();
+            let ty = *record.add(18);
+            let name = CStr::from_ptr(record.add(19) as *const libc::c_char)
+                .to_string_lossy()
+                .into_owned();
+
+            self.pos += reclen as usize;
+
+            Ok(Some(DirEntry { ino, off, ty, name }))
+        }
+    }
+}